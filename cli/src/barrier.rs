@@ -0,0 +1,107 @@
+//! 子事务屏障
+//!
+//! RM 在自己的业务事务里调用 [`BranchBarrier::call`]，屏障会把当前分支写入本地的
+//! `luwu_barrier` 表，由唯一索引保证以下情况自动跳过业务逻辑：
+//!
+//! - 重复请求（幂等）
+//! - try/正向操作 没有执行过的 cancel/补偿（空补偿）
+//! - cancel/补偿 之后才到达的 try/正向操作（悬挂）
+use std::future::Future;
+
+use tokio_postgres::Transaction;
+use tracing::debug;
+
+pub use luwu::models::transaction::BranchParam;
+
+/// 屏障表，需要建在业务数据库中。
+pub const SCHEMA: &str = r#"CREATE TABLE IF NOT EXISTS luwu_barrier (
+    id BIGSERIAL PRIMARY KEY,
+    gid UUID NOT NULL,
+    branch_id UUID NOT NULL,
+    branch_type VARCHAR(45) NOT NULL,
+    reason VARCHAR(45) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (gid, branch_id, branch_type)
+)"#;
+
+#[derive(Debug, Clone)]
+pub struct BranchBarrier {
+    param: BranchParam,
+}
+
+impl BranchBarrier {
+    pub fn new(param: BranchParam) -> BranchBarrier {
+        BranchBarrier { param }
+    }
+
+    pub fn param(&self) -> &BranchParam {
+        &self.param
+    }
+
+    /// Run `busi` inside the business transaction `tx` if the barrier lets it pass,
+    /// `Ok(None)` means it is skipped, and RM should reply success as usual.
+    ///
+    /// The barrier rows are written by `tx`, so they are committed or rolled back
+    /// together with the business changes.
+    pub async fn call<F, Fut, T, E>(&self, tx: &Transaction<'_>, busi: F) -> Result<Option<T>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<tokio_postgres::Error>,
+    {
+        let branch_type = self.param.branch_type();
+        let current = self.insert(tx, branch_type, branch_type).await?;
+        // 补偿类的操作，先占住对应的正向操作，之后到达的正向操作会被跳过
+        let origin = match origin(branch_type) {
+            Some(origin) => self.insert(tx, origin, branch_type).await?,
+            None => 0,
+        };
+        if current == 0 {
+            debug!(
+                "barrier skipped duplicated {} of {}/{}",
+                branch_type,
+                self.param.gid(),
+                self.param.branch_id()
+            );
+            return Ok(None);
+        }
+        if origin > 0 {
+            debug!(
+                "barrier skipped empty {} of {}/{}",
+                branch_type,
+                self.param.gid(),
+                self.param.branch_id()
+            );
+            return Ok(None);
+        }
+        busi().await.map(Some)
+    }
+
+    async fn insert(
+        &self,
+        tx: &Transaction<'_>,
+        branch_type: &str,
+        reason: &str,
+    ) -> Result<u64, tokio_postgres::Error> {
+        tx.execute(
+            "INSERT INTO luwu_barrier (gid, branch_id, branch_type, reason) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+            &[
+                &self.param.gid(),
+                &self.param.branch_id(),
+                &branch_type,
+                &reason,
+            ],
+        )
+        .await
+    }
+}
+
+/// The forward branch type which the compensating one reverts.
+fn origin(branch_type: &str) -> Option<&'static str> {
+    match branch_type {
+        "cancel" => Some("try"),
+        "on_reverting" => Some("on_committing"),
+        "compensation" => Some("action"),
+        _ => None,
+    }
+}
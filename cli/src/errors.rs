@@ -0,0 +1,48 @@
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("RequestError {0}")]
+    HttpError(#[from] reqwest::Error),
+    #[error("Can not submit transaction: {0}")]
+    CannotSubmit(String),
+    #[error("Transaction is not abortable: {0}")]
+    NotAbortable(String),
+    #[error("Branch registering is denied: {0}")]
+    RegisterDenied(String),
+    #[error("Invalid transaction definition: {0}")]
+    InvalidDefinition(String),
+    #[error("Luwu responded {code}: {message}")]
+    Server { code: u16, message: String },
+    #[error("Branch failed: {0}")]
+    BranchFailed(String),
+    #[error("Unexpected response: {0}")]
+    UnexpectedResponse(String),
+}
+
+/// Error body of luwu, see `luwu::errors::ErrorResponse`.
+#[derive(Debug, Deserialize)]
+struct ErrResponse {
+    message: String,
+    code: u16,
+}
+
+impl Error {
+    pub fn from_code(code: u16, message: String) -> Error {
+        match code {
+            5010 => Error::CannotSubmit(message),
+            5020 => Error::NotAbortable(message),
+            5030 => Error::RegisterDenied(message),
+            5040 => Error::InvalidDefinition(message),
+            _ => Error::Server { code, message },
+        }
+    }
+
+    pub(crate) fn from_body(body: String) -> Error {
+        match serde_json::from_str::<ErrResponse>(&body) {
+            Ok(ErrResponse { message, code }) => Error::from_code(code, message),
+            Err(_) => Error::UnexpectedResponse(body),
+        }
+    }
+}
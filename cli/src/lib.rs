@@ -1,9 +1,150 @@
+use tracing::debug;
+
+pub use luwu::models::transaction::Gid;
+use luwu::models::transaction::{TCCBranchCreation, TransactionCreation};
+
+pub mod barrier;
+pub mod errors;
 pub mod message;
+pub mod saga;
+pub mod tcc;
+
+pub use errors::Error;
+pub use message::Message;
+pub use saga::Saga;
+pub use tcc::Tcc;
+
+/// Handle of a luwu server.
+#[derive(Debug, Clone)]
+pub struct Luwu {
+    server: String,
+    cli: reqwest::Client,
+}
+
+impl Luwu {
+    /// `server` is the address of luwu, for example `http://127.0.0.1:8000`.
+    pub fn new<S: Into<String>>(server: S) -> Luwu {
+        let server: String = server.into();
+        Luwu {
+            server: server.trim_end_matches('/').to_string(),
+            cli: reqwest::Client::new(),
+        }
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn saga(&self) -> Saga {
+        Saga::new(self.clone())
+    }
+
+    pub fn message(&self) -> Message {
+        Message::new(self.clone())
+    }
+
+    pub async fn tcc(&self) -> Result<Tcc, Error> {
+        Tcc::begin(self.clone()).await
+    }
+
+    /// Generate a new gid.
+    pub async fn gid(&self) -> Result<Gid, Error> {
+        let resp = self.cli.get(self.url("/gid")).header("x-api-version", "1.0.0").send().await;
+        let body = check(resp).await?;
+        body.trim()
+            .parse()
+            .map_err(|_| Error::UnexpectedResponse(body))
+    }
+
+    /// Create a transaction, and submit it at once if `submit` is true.
+    pub(crate) async fn create(&self, creation: &TransactionCreation, submit: bool) -> Result<Gid, Error> {
+        debug!("creating transaction {:?} submit: {}", creation, submit);
+        let resp = self
+            .cli
+            .post(self.url("/transactions"))
+            .query(&[("submit", submit)])
+            .header("x-api-version", "1.0.0")
+            .json(creation)
+            .send()
+            .await;
+        let body = check(resp).await?;
+        body.trim()
+            .parse()
+            .map_err(|_| Error::UnexpectedResponse(body))
+    }
+
+    pub(crate) async fn submit(&self, gid: Gid) -> Result<(), Error> {
+        debug!("submitting transaction {}", gid);
+        let resp = self
+            .cli
+            .put(self.url(&format!("/transactions/{}/submitting", gid)))
+            .header("x-api-version", "1.0.0")
+            .send()
+            .await;
+        check(resp).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn abort(&self, gid: Gid) -> Result<(), Error> {
+        debug!("aborting transaction {}", gid);
+        let resp = self
+            .cli
+            .put(self.url(&format!("/transactions/{}/aborting", gid)))
+            .header("x-api-version", "1.0.0")
+            .send()
+            .await;
+        check(resp).await?;
+        Ok(())
+    }
+
+    pub(crate) async fn register_tcc_branch(
+        &self,
+        gid: Gid,
+        branch_id: Gid,
+        branch: &TCCBranchCreation,
+    ) -> Result<(), Error> {
+        debug!("registering tcc branch {}/{}", gid, branch_id);
+        let resp = self
+            .cli
+            .post(self.url(&format!("/transactions/{}/branches/{}/tcc", gid, branch_id)))
+            .header("x-api-version", "1.0.0")
+            .json(branch)
+            .send()
+            .await;
+        check(resp).await?;
+        Ok(())
+    }
+
+    pub(crate) fn client(&self) -> &reqwest::Client {
+        &self.cli
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api{}", self.server, path)
+    }
+}
+
+/// Return the body of a successful response, or the error luwu responded.
+async fn check(resp: Result<reqwest::Response, reqwest::Error>) -> Result<String, Error> {
+    let resp = resp?;
+    let status = resp.status();
+    let body = resp.text().await?;
+    if status.is_success() {
+        Ok(body)
+    } else {
+        Err(Error::from_body(body))
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn it_maps_error_codes() {
+        let err = Error::from_body(r#"{"message": "denied", "code": 5030}"#.to_string());
+        assert!(matches!(err, Error::RegisterDenied(_)));
+        let err = Error::from_body("oops".to_string());
+        assert!(matches!(err, Error::UnexpectedResponse(_)));
     }
 }
@@ -1,39 +1,93 @@
-use tracing::{debug, info};
-use serde::{Serialize,Desrialize};
+use tracing::debug;
 
-pub use luwu::processors::{Message,MessageStep};
+use luwu::models::transaction::{Gid, TransactionCreation};
+use luwu::models::RequestOptions;
+use luwu::processors::{self, MessageStep, ProcessorType};
+
+use crate::{Error, Luwu};
+
+/// Builder of a transactional message.
+#[derive(Debug)]
+pub struct Message {
+    luwu: Luwu,
+    gid: Option<Gid>,
+    payload: String,
+    steps: Vec<MessageStep>,
+    query_prepared: String,
+    prepared: bool,
+    options: Option<RequestOptions>,
+}
 
 impl Message {
-    fn new(server: String, gid: String) -> Message {
-	}
+    pub fn new(luwu: Luwu) -> Message {
+        Message {
+            luwu,
+            gid: None,
+            payload: String::new(),
+            steps: Vec::new(),
+            query_prepared: String::new(),
+            prepared: false,
+            options: None,
+        }
+    }
+
+    /// Use the gid generated by [`Luwu::gid`], otherwise luwu generates one.
+    pub fn with_gid(mut self, gid: Gid) -> Message {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Headers, credential and timeout used by luwu to call all steps.
+    pub fn with_options(mut self, options: RequestOptions) -> Message {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn gid(&self) -> Option<Gid> {
+        self.gid
+    }
 
     // Add a step
-    fn add(&mut self, callback: String, payload: String) {
-	    debug!("message {} add {} {:?}", self.gid, back, paylaod);
-	    // step = MsgStep{
-	    // 	Action: action,
-	    // 	Data:   common.MustMarshalString(postData),
-	    // }
-	    // s.Steps = append(s.Steps, step)
-	    // return s
-    }
-
-    // Submit submit the msg
-    async fn submit(&mut self) -> Result<(), ()> {
-	    debug!("committing {} body: {:?}", s.Gid, &s.MsgData);
-        let cli = reqwest::Client::new();
-        // cli.post();
-	    // let resp = common.RestyClient.R().SetBody(&s.MsgData).Post(fmt.Sprintf("%s/submit", s.Server))
-	    // return CheckDtmResponse(resp, err)
-        Ok(())
+    pub fn add(mut self, callback: String, payload: String) -> Message {
+        debug!("message add {} {:?}", callback, payload);
+        self.steps.push(MessageStep::new(payload, callback));
+        self
     }
 
     // prepare the message
-    async fn prepare(&mut self, query_prepared: string) -> Result<(), ()> {
-	    // s.QueryPrepared = common.OrString(queryPrepared, s.QueryPrepared):
-	    debug!("preparing {} body: {:?}", self.gid, &self.paylaod);
-	    // resp, err := common.RestyClient.R().SetBody(&s.MsgData).Post(fmt.Sprintf("%s/prepare", s.Server))
-	    // return CheckDtmResponse(resp, err)
-        Ok(())
+    pub async fn prepare(&mut self, query_prepared: String) -> Result<Gid, Error> {
+        self.query_prepared = query_prepared;
+        let gid = self.create(false).await?;
+        self.prepared = true;
+        Ok(gid)
+    }
+
+    // Submit submit the msg, it is created and submitted in one call if not prepared.
+    pub async fn submit(&mut self) -> Result<Gid, Error> {
+        match (self.prepared, self.gid) {
+            (true, Some(gid)) => {
+                self.luwu.submit(gid).await?;
+                Ok(gid)
+            }
+            _ => self.create(true).await,
+        }
+    }
+
+    async fn create(&mut self, submit: bool) -> Result<Gid, Error> {
+        let message = processors::Message::new(self.steps.clone(), self.query_prepared.clone());
+        let mut creation = TransactionCreation::new(
+            ProcessorType::Message(message),
+            self.payload.clone(),
+            self.query_prepared.clone(),
+        );
+        if let Some(gid) = self.gid {
+            creation = creation.with_gid(gid);
+        }
+        if let Some(options) = self.options.clone() {
+            creation = creation.with_options(options);
+        }
+        let gid = self.luwu.create(&creation, submit).await?;
+        self.gid = Some(gid);
+        Ok(gid)
     }
 }
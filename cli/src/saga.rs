@@ -1,23 +1,97 @@
-pub use luwu::processors::{Saga, SagaStep};
+use tracing::debug;
+
+use luwu::models::transaction::{Gid, TransactionCreation};
+use luwu::models::RequestOptions;
+use luwu::processors::{self, ProcessorType, SagaStep};
+
+use crate::{Error, Luwu};
+
+/// Builder of a saga transaction.
+#[derive(Debug)]
+pub struct Saga {
+    luwu: Luwu,
+    gid: Option<Gid>,
+    payload: String,
+    steps: Vec<SagaStep>,
+    concurrent: bool,
+    options: Option<RequestOptions>,
+}
 
 impl Saga {
+    pub fn new(luwu: Luwu) -> Saga {
+        Saga {
+            luwu,
+            gid: None,
+            payload: String::new(),
+            steps: Vec::new(),
+            concurrent: false,
+            options: None,
+        }
+    }
+
+    /// Use the gid generated by [`Luwu::gid`], otherwise luwu generates one.
+    pub fn with_gid(mut self, gid: Gid) -> Saga {
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn with_payload(mut self, payload: String) -> Saga {
+        self.payload = payload;
+        self
+    }
+
+    /// Headers, credential and timeout used by luwu to call all steps.
+    pub fn with_options(mut self, options: RequestOptions) -> Saga {
+        self.options = Some(options);
+        self
+    }
+
+    /// Run steps without dependencies concurrently, see [`Saga::add_after`].
+    pub fn concurrent(mut self) -> Saga {
+        self.concurrent = true;
+        self
+    }
+
     // Add add a saga step
-    async fn add(&mut self, on_committing: string, on_reverting: String, payload: String) -> Result<(), ()> {
-    	debug!("saga {} add on_committing:`{}` on_revert:`{}` {:?}", self.gid, on_committing, on_reverting, postData);
-    	let step = SagaStep{
-    		on_committing,
-    		on_reverting,
-    		payload,
-    	};
-    	self.steps.push(step);
-        Ok(())
-    }
-    
+    pub fn add(self, on_committing: String, on_reverting: String, payload: String) -> Saga {
+        self.add_after(on_committing, on_reverting, payload, Vec::new())
+    }
+
+    /// Add a step which runs after the steps at `depends_on`, only works in concurrent mode.
+    pub fn add_after(
+        mut self,
+        on_committing: String,
+        on_reverting: String,
+        payload: String,
+        depends_on: Vec<usize>,
+    ) -> Saga {
+        debug!(
+            "saga add on_committing:`{}` on_reverting:`{}` {:?}",
+            on_committing, on_reverting, payload
+        );
+        let step = SagaStep::new(on_committing, on_reverting, payload).with_depends_on(depends_on);
+        self.steps.push(step);
+        self
+    }
+
     // Submit the saga transaction
-    async fn submit(&self) -> Result<(), ()> {
-    	debug!("committing {} body: {:?}", self.gid, &self.payload);
-    	// resp, err := common.RestyClient.R().SetBody(&s.SagaData).Post(fmt.Sprintf("%s/submit", s.Server))
-    	// return CheckDtmResponse(resp, err)
-        Ok(())
+    pub async fn submit(self) -> Result<Gid, Error> {
+        let Saga {
+            luwu,
+            gid,
+            payload,
+            steps,
+            concurrent,
+            options,
+        } = self;
+        let r#type = ProcessorType::Saga(processors::Saga::new(steps, concurrent));
+        let mut creation = TransactionCreation::new(r#type, payload, String::new());
+        if let Some(gid) = gid {
+            creation = creation.with_gid(gid);
+        }
+        if let Some(options) = options {
+            creation = creation.with_options(options);
+        }
+        luwu.create(&creation, true).await
     }
 }
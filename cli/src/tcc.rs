@@ -1,97 +1,112 @@
-use luwu::processors::{TCC, ProcessorType};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
 
-impl TCC {
-// TccGlobalTransaction begin a tcc global transaction
-// dtm dtm服务器地址
-// gid 全局事务id
-// tccFunc tcc事务函数，里面会定义全局事务的分支
-async fn transaction<F>(&self, luwu: String, gid: String, handle: F) -> Result<(), ()>
-where F: FnOnce(&TCC) -> Result<(), ()>
-{
-    /*
-	defer func() {
-		var resp *resty.Response
-		var err error
-		var x interface{}
-		if x = recover(); x != nil || rerr != nil {
-			resp, err = common.RestyClient.R().SetBody(data).Post(dtm + "/abort")
-		} else {
-			resp, err = common.RestyClient.R().SetBody(data).Post(dtm + "/submit")
-		}
-		err2 := CheckDtmResponse(resp, err)
-		if err2 != nil {
-			logrus.Errorf("submitting or abort global transaction error: %v", err2)
-		}
-		if x != nil {
-			panic(x)
-		}
-	}()
-    */
-    let cli = reqwest::Client::new();
-    let resp = cli.post(format!("{}/api/preparing", luwu))
-        .header("x-api-version".parse().unwrap(), "1.0.0".parse().unwrap())
-        .header(reqwest::header::CONTENT_TYPE, "application/json".parse().unwrap())
-        .json(ProcessorType::TCC(TCC))?
-        .send()
-        .await?;
-    //
-    match handle(tcc) {
-        Ok(_) => {
-			// resp, err = common.RestyClient.R().SetBody(data).Post(dtm + "/submit")
-        },
-        Err(_) => {
-			// resp, err = common.RestyClient.R().SetBody(data).Post(dtm + "/abort")
+use futures::FutureExt;
+use tracing::{debug, error};
+
+use luwu::models::transaction::{BranchParam, Gid, TCCBranchCreation, TransactionCreation};
+use luwu::processors::{Outcome, ProcessorType, TCC};
+
+use crate::{Error, Luwu};
+
+/// A tcc global transaction.
+#[derive(Debug, Clone)]
+pub struct Tcc {
+    luwu: Luwu,
+    gid: Gid,
+}
+
+impl Tcc {
+    /// Create the global transaction on luwu.
+    pub async fn begin(luwu: Luwu) -> Result<Tcc, Error> {
+        let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new());
+        let gid = luwu.create(&creation, false).await?;
+        Ok(Tcc { luwu, gid })
+    }
+
+    /// Like [`Tcc::begin`], but luwu cancels all branches if the transaction is
+    /// not submitted within `timeout` seconds.
+    pub async fn begin_with_timeout(luwu: Luwu, timeout: i64) -> Result<Tcc, Error> {
+        let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new())
+            .with_timeout_to_fail(timeout);
+        let gid = luwu.create(&creation, false).await?;
+        Ok(Tcc { luwu, gid })
+    }
+
+    /// Run a global transaction, `handle` registers and tries branches by [`Tcc::branch`].
+    ///
+    /// When `handle` returns `Ok` all branches are confirmed, when it returns `Err` or
+    /// panics all branches are canceled, and the error or panic is passed through.
+    pub async fn transaction<F, Fut, T, E>(luwu: Luwu, handle: F) -> Result<T, E>
+    where
+        F: FnOnce(Tcc) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+        E: From<Error>,
+    {
+        let tcc = Tcc::begin(luwu).await?;
+        let result = AssertUnwindSafe(handle(tcc.clone())).catch_unwind().await;
+        match result {
+            Ok(Ok(value)) => {
+                tcc.submit().await?;
+                Ok(value)
+            }
+            Ok(Err(err)) => {
+                if let Err(abort_err) = tcc.abort().await {
+                    error!("aborting tcc transaction {} failed: {}", tcc.gid, abort_err);
+                }
+                Err(err)
+            }
+            Err(cause) => {
+                if let Err(abort_err) = tcc.abort().await {
+                    error!("aborting tcc transaction {} failed: {}", tcc.gid, abort_err);
+                }
+                panic::resume_unwind(cause)
+            }
         }
     }
-	Ok(())
-}
 
-// TccFromReq tcc from request info
-// func TccFromReq(c *gin.Context) (*Tcc, error) {
-// 	tcc := &Tcc{
-// 		Dtm:         c.Query("dtm"),
-// 		Gid:         c.Query("gid"),
-// 		IDGenerator: IDGenerator{parentID: c.Query("branch_id")},
-// 	}
-// 	if tcc.Dtm == "" || tcc.Gid == "" {
-// 		return nil, fmt.Errorf("bad tcc info. dtm: %s, gid: %s", tcc.Dtm, tcc.Gid)
-// 	}
-// 	return tcc, nil
-// }
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
 
     // call a tcc branch
     // 函数首先注册子事务的所有分支，成功后调用try分支，返回try分支的调用结果
-    async fn branch(&self, payload: String, tryURL: String, confirmURL: String, cancelURL: String) -> Result<(), ()> {
-    	// branchID := t.NewBranchID()
-    	resp, err := common.RestyClient.R().
-    		SetBody(&M{
-    			"gid":        t.Gid,
-    			"branch_id":  branchID,
-    			"trans_type": "tcc",
-    			"status":     "prepared",
-    			"data":       string(common.MustMarshal(body)),
-    			"try":        tryURL,
-    			"confirm":    confirmURL,
-    			"cancel":     cancelURL,
-    		}).
-    		Post(t.Dtm + "/registerTccBranch")
-    	err = CheckDtmResponse(resp, err)
-    	if err != nil {
-    		return resp, err
-    	}
-    	resp, err = common.RestyClient.R().
-    		SetBody(body).
-    		SetQueryParams(common.MS{
-    			"dtm":         t.Dtm,
-    			"gid":         t.Gid,
-    			"branch_id":   branchID,
-    			"trans_type":  "tcc",
-    			"branch_type": "try",
-    		}).
-    		Post(tryURL)
-    	if err == nil && strings.Contains(resp.String(), "FAILURE") {
-    		err = fmt.Errorf("branch return failure: %s", resp.String())
-    	}
-    	return resp, err
+    pub async fn branch(
+        &self,
+        payload: String,
+        try_url: String,
+        confirm_url: String,
+        cancel_url: String,
+    ) -> Result<String, Error> {
+        let branch_id = Gid::new_v4();
+        let creation = TCCBranchCreation::new(payload.clone(), try_url.clone(), confirm_url, cancel_url);
+        self.luwu.register_tcc_branch(self.gid, branch_id, &creation).await?;
+        let params = BranchParam::new(self.gid, branch_id, "tcc".to_string(), "try".to_string());
+        debug!("trying {} {:?}", try_url, params);
+        let resp = self
+            .luwu
+            .client()
+            .post(&try_url)
+            .query(&params)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(payload)
+            .send()
+            .await?;
+        let body = resp.text().await?;
+        match Outcome::from_body(&body) {
+            Outcome::Succeed => Ok(body),
+            Outcome::Failed => Err(Error::BranchFailed(body)),
+            Outcome::Retry => Err(Error::UnexpectedResponse(body)),
+        }
+    }
+
+    /// Confirm all branches.
+    pub async fn submit(&self) -> Result<(), Error> {
+        self.luwu.submit(self.gid).await
+    }
+
+    /// Cancel all branches.
+    pub async fn abort(&self) -> Result<(), Error> {
+        self.luwu.abort(self.gid).await
     }
 }
@@ -0,0 +1,34 @@
+CREATE TABLE IF NOT EXISTS tx_transactions (
+    gid CHAR(36) PRIMARY KEY,
+    state BIGINT NOT NULL,
+    type JSON NOT NULL,
+    payload TEXT NOT NULL,
+    query_prepared TEXT NOT NULL,
+    committed_at DATETIME(6),
+    finished_at DATETIME(6),
+    rollbacked_at DATETIME(6),
+    delay BIGINT NOT NULL DEFAULT 0,
+    scheduled_at DATETIME(6),
+    owner CHAR(36),
+    lease_expires_at DATETIME(6),
+    created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    last_modified DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    INDEX tx_transactions_scheduled_at_idx (scheduled_at),
+    INDEX tx_transactions_state_idx (state)
+);
+
+CREATE TABLE IF NOT EXISTS tx_transaction_branches (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    gid CHAR(36) NOT NULL,
+    branch_id CHAR(36) NOT NULL,
+    type VARCHAR(45) NOT NULL,
+    state BIGINT NOT NULL,
+    url TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    finished_at DATETIME(6),
+    rollbacked_at DATETIME(6),
+    created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    last_modified DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    UNIQUE (gid, branch_id, type),
+    FOREIGN KEY (gid) REFERENCES tx_transactions (gid)
+);
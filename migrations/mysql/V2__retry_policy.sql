@@ -0,0 +1,4 @@
+ALTER TABLE tx_transactions ADD COLUMN retry_policy JSON;
+
+ALTER TABLE tx_transaction_branches ADD COLUMN retry_policy JSON;
+ALTER TABLE tx_transaction_branches ADD COLUMN attempts BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE tx_transactions ADD COLUMN timeout_to_fail BIGINT;
+ALTER TABLE tx_transactions ADD COLUMN reason TEXT;
@@ -0,0 +1,6 @@
+ALTER TABLE tx_transactions ADD COLUMN processor VARCHAR(45) NOT NULL DEFAULT '';
+UPDATE tx_transactions SET processor = JSON_UNQUOTE(JSON_EXTRACT(type, '$.type'));
+
+CREATE INDEX tx_transactions_processor_idx ON tx_transactions (processor);
+CREATE INDEX tx_transactions_created_at_idx ON tx_transactions (created_at, gid);
+CREATE INDEX tx_transactions_last_modified_idx ON tx_transactions (last_modified, gid);
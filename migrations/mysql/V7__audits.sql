@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS tx_audits (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    gid CHAR(36) NOT NULL,
+    operator VARCHAR(128) NOT NULL,
+    action VARCHAR(45) NOT NULL,
+    reason TEXT NOT NULL,
+    created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    INDEX tx_audits_gid_idx (gid),
+    FOREIGN KEY (gid) REFERENCES tx_transactions (gid)
+);
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS tx_events (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    gid CHAR(36) NOT NULL,
+    kind VARCHAR(16) NOT NULL,
+    branch_id CHAR(36),
+    branch_type VARCHAR(45),
+    state BIGINT,
+    url TEXT,
+    status BIGINT,
+    latency BIGINT,
+    delay BIGINT,
+    detail TEXT,
+    created_at DATETIME(6) NOT NULL DEFAULT CURRENT_TIMESTAMP(6),
+    INDEX tx_events_gid_idx (gid),
+    FOREIGN KEY (gid) REFERENCES tx_transactions (gid)
+);
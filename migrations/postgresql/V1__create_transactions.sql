@@ -0,0 +1,34 @@
+CREATE TABLE IF NOT EXISTS tx_transactions (
+    gid UUID PRIMARY KEY,
+    state BIGINT NOT NULL,
+    type JSONB NOT NULL,
+    payload TEXT NOT NULL DEFAULT '',
+    query_prepared TEXT NOT NULL DEFAULT '',
+    committed_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+    rollbacked_at TIMESTAMPTZ,
+    delay BIGINT NOT NULL DEFAULT 0,
+    scheduled_at TIMESTAMPTZ,
+    owner UUID,
+    lease_expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_modified TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS tx_transactions_scheduled_at_idx ON tx_transactions (scheduled_at);
+CREATE INDEX IF NOT EXISTS tx_transactions_state_idx ON tx_transactions (state);
+
+CREATE TABLE IF NOT EXISTS tx_transaction_branches (
+    id BIGSERIAL PRIMARY KEY,
+    gid UUID NOT NULL REFERENCES tx_transactions (gid),
+    branch_id UUID NOT NULL,
+    type VARCHAR(45) NOT NULL,
+    state BIGINT NOT NULL,
+    url TEXT NOT NULL DEFAULT '',
+    payload TEXT NOT NULL DEFAULT '',
+    finished_at TIMESTAMPTZ,
+    rollbacked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    last_modified TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (gid, branch_id, type)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE tx_transactions ADD COLUMN IF NOT EXISTS retry_policy JSONB;
+
+ALTER TABLE tx_transaction_branches ADD COLUMN IF NOT EXISTS retry_policy JSONB;
+ALTER TABLE tx_transaction_branches ADD COLUMN IF NOT EXISTS attempts BIGINT NOT NULL DEFAULT 0;
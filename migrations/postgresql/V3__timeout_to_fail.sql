@@ -0,0 +1,2 @@
+ALTER TABLE tx_transactions ADD COLUMN IF NOT EXISTS timeout_to_fail BIGINT;
+ALTER TABLE tx_transactions ADD COLUMN IF NOT EXISTS reason TEXT;
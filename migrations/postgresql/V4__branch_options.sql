@@ -0,0 +1 @@
+ALTER TABLE tx_transaction_branches ADD COLUMN IF NOT EXISTS options JSONB;
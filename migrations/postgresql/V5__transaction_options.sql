@@ -0,0 +1 @@
+ALTER TABLE tx_transactions ADD COLUMN IF NOT EXISTS options JSONB;
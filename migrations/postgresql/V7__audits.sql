@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS tx_audits (
+    id BIGSERIAL PRIMARY KEY,
+    gid UUID NOT NULL REFERENCES tx_transactions (gid),
+    operator VARCHAR(128) NOT NULL,
+    action VARCHAR(45) NOT NULL,
+    reason TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS tx_audits_gid_idx ON tx_audits (gid);
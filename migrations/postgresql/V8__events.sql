@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS tx_events (
+    id BIGSERIAL PRIMARY KEY,
+    gid UUID NOT NULL REFERENCES tx_transactions (gid),
+    kind VARCHAR(16) NOT NULL,
+    branch_id UUID,
+    branch_type VARCHAR(45),
+    state BIGINT,
+    url TEXT,
+    status BIGINT,
+    latency BIGINT,
+    delay BIGINT,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS tx_events_gid_idx ON tx_events (gid);
@@ -0,0 +1,34 @@
+CREATE TABLE IF NOT EXISTS tx_transactions (
+    gid TEXT PRIMARY KEY,
+    state INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    payload TEXT NOT NULL DEFAULT '',
+    query_prepared TEXT NOT NULL DEFAULT '',
+    committed_at DATETIME,
+    finished_at DATETIME,
+    rollbacked_at DATETIME,
+    delay INTEGER NOT NULL DEFAULT 0,
+    scheduled_at DATETIME,
+    owner TEXT,
+    lease_expires_at DATETIME,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_modified DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS tx_transactions_scheduled_at_idx ON tx_transactions (scheduled_at);
+CREATE INDEX IF NOT EXISTS tx_transactions_state_idx ON tx_transactions (state);
+
+CREATE TABLE IF NOT EXISTS tx_transaction_branches (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    gid TEXT NOT NULL REFERENCES tx_transactions (gid),
+    branch_id TEXT NOT NULL,
+    type TEXT NOT NULL,
+    state INTEGER NOT NULL,
+    url TEXT NOT NULL DEFAULT '',
+    payload TEXT NOT NULL DEFAULT '',
+    finished_at DATETIME,
+    rollbacked_at DATETIME,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_modified DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (gid, branch_id, type)
+);
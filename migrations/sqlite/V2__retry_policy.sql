@@ -0,0 +1,4 @@
+ALTER TABLE tx_transactions ADD COLUMN retry_policy TEXT;
+
+ALTER TABLE tx_transaction_branches ADD COLUMN retry_policy TEXT;
+ALTER TABLE tx_transaction_branches ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+ALTER TABLE tx_transactions ADD COLUMN timeout_to_fail INTEGER;
+ALTER TABLE tx_transactions ADD COLUMN reason TEXT;
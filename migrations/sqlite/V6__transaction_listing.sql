@@ -0,0 +1,6 @@
+ALTER TABLE tx_transactions ADD COLUMN processor TEXT NOT NULL DEFAULT '';
+UPDATE tx_transactions SET processor = json_extract(type, '$.type');
+
+CREATE INDEX IF NOT EXISTS tx_transactions_processor_idx ON tx_transactions (processor);
+CREATE INDEX IF NOT EXISTS tx_transactions_created_at_idx ON tx_transactions (created_at, gid);
+CREATE INDEX IF NOT EXISTS tx_transactions_last_modified_idx ON tx_transactions (last_modified, gid);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS tx_audits (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    gid TEXT NOT NULL REFERENCES tx_transactions (gid),
+    operator TEXT NOT NULL,
+    action TEXT NOT NULL,
+    reason TEXT NOT NULL DEFAULT '',
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS tx_audits_gid_idx ON tx_audits (gid);
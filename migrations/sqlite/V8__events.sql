@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS tx_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    gid TEXT NOT NULL REFERENCES tx_transactions (gid),
+    kind TEXT NOT NULL,
+    branch_id TEXT,
+    branch_type TEXT,
+    state INTEGER,
+    url TEXT,
+    status INTEGER,
+    latency INTEGER,
+    delay INTEGER,
+    detail TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS tx_events_gid_idx ON tx_events (gid);
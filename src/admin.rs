@@ -0,0 +1,169 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::{json::Json, uuid::Uuid};
+use rocket_versioning::Versioning;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::config::CONFIG;
+use crate::database::DB;
+use crate::errors::{self, ErrorResponse};
+use crate::models::transaction::{Gid, State, Transaction};
+use crate::models::Audit;
+use crate::processors::ProcessorType;
+use crate::responder::DynResponse;
+use crate::routes::{spawn_process, state_error};
+
+/// The operator whose token in `Authorization: Bearer <token>` is one of `admin_tokens`.
+pub struct Operator(String);
+
+impl Operator {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Operator {
+    type Error = ();
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = request
+            .headers()
+            .get_one("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let operator = match (CONFIG.get(), token) {
+            (Some(config), Some(token)) => config.operator(token),
+            _ => None,
+        };
+        match operator {
+            Some(operator) => request::Outcome::Success(Operator(operator.to_string())),
+            None => {
+                warn!("unauthorized admin request {}", request.uri());
+                request::Outcome::Failure((Status::Unauthorized, ()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AdminAction {
+    reason: String,
+}
+
+impl AdminAction {
+    fn reason(&self, action: &str) -> Result<&str, ErrorResponse> {
+        match self.reason.trim() {
+            "" => Err(ErrorResponse::new(errors::Error::ReasonRequired(action.to_string()), 5070)),
+            reason => Ok(reason),
+        }
+    }
+}
+
+fn denied(action: &str, tx: &Transaction) -> ErrorResponse {
+    let err = errors::Error::AdminActionDenied(action.to_string(), tx.gid(), tx.state().tag().to_string());
+    ErrorResponse::new(err, 5070)
+}
+
+async fn audit(db: &DB, gid: Gid, operator: &Operator, action: &str, reason: &str) -> Result<(), errors::Error> {
+    let audit = Audit::new(gid, operator.name().to_string(), action.to_string(), reason.to_string());
+    db.as_ref().insert_audit(&audit).await
+}
+
+/// Reset attempts of branches without a definite result and process the
+/// transaction now, while no one else is processing it.
+#[put("/transactions/<gid>/retry", data = "<action>")]
+async fn retry(
+    _v: Versioning<1, 0>,
+    operator: Operator,
+    db: DB,
+    gid: Uuid,
+    action: Json<AdminAction>,
+) -> Result<String, ErrorResponse> {
+    let reason = action.reason("retry")?;
+    let mut tx = Transaction::load(gid, db.as_ref()).await?;
+    match tx.state() {
+        State::Submitted | State::Aborting => {}
+        _ => return Err(denied("retry", &tx)),
+    }
+    // 与 mark-succeed 一样，持有租约时才重置，避免与正在处理的实例同时改动分支
+    if !tx.acquire_lease(db.as_ref()).await? {
+        let err = errors::Error::AdminActionDenied("retry".to_string(), gid, "being processed".to_string());
+        return Err(ErrorResponse::new(err, 5070));
+    }
+    let retried = tx.retry(db.as_ref()).await;
+    tx.release_lease(db.as_ref()).await?;
+    retried?;
+    audit(&db, gid, &operator, "retry", reason).await?;
+    spawn_process(tx, db.into()).await?;
+    Ok("SUCCESS".to_string())
+}
+
+/// Roll back a prepared transaction, or a submitted saga or state machine which
+/// can still be compensated.
+#[put("/transactions/<gid>/force-abort", data = "<action>")]
+async fn force_abort(
+    _v: Versioning<1, 0>,
+    operator: Operator,
+    db: DB,
+    gid: Uuid,
+    action: Json<AdminAction>,
+) -> Result<String, ErrorResponse> {
+    let reason = action.reason("abort")?;
+    let mut tx = Transaction::load(gid, db.as_ref()).await?;
+    // 已提交的 xa、tcc 和消息只能向前完成，没有回滚的路径
+    match (tx.r#type(), tx.state()) {
+        (_, State::Prepared) => {}
+        (ProcessorType::Saga(_), State::Submitted) | (ProcessorType::StateMachine(_), State::Submitted) => {}
+        _ => return Err(denied("abort", &tx)),
+    }
+    tx.abort(db.as_ref(), &format!("aborted by {}: {}", operator.name(), reason))
+        .await
+        .map_err(|err| state_error(err, 5070))?;
+    audit(&db, gid, &operator, "force-abort", reason).await?;
+    spawn_process(tx, db.into()).await?;
+    Ok("SUCCESS".to_string())
+}
+
+/// Finish an unfinished transaction as succeed without calling RMs, while no one
+/// is processing it.
+#[put("/transactions/<gid>/mark-succeed", data = "<action>")]
+async fn mark_succeed(
+    _v: Versioning<1, 0>,
+    operator: Operator,
+    db: DB,
+    gid: Uuid,
+    action: Json<AdminAction>,
+) -> Result<String, ErrorResponse> {
+    let reason = action.reason("mark succeed")?;
+    let mut tx = Transaction::load(gid, db.as_ref()).await?;
+    match tx.state() {
+        State::Prepared | State::Submitted | State::Aborting => {}
+        _ => return Err(denied("mark succeed", &tx)),
+    }
+    // 持有租约期间不会有人同时调用 RM
+    if !tx.acquire_lease(db.as_ref()).await? {
+        let err = errors::Error::AdminActionDenied("mark succeed".to_string(), gid, "being processed".to_string());
+        return Err(ErrorResponse::new(err, 5070));
+    }
+    let marked = tx
+        .mark_succeed(db.as_ref(), &format!("marked succeed by {}: {}", operator.name(), reason))
+        .await;
+    tx.release_lease(db.as_ref()).await?;
+    marked.map_err(|err| state_error(err, 5070))?;
+    audit(&db, gid, &operator, "mark-succeed", reason).await?;
+    Ok("SUCCESS".to_string())
+}
+
+#[get("/transactions/<gid>/audits")]
+async fn audits(
+    _v: Versioning<1, 0>,
+    _operator: Operator,
+    db: DB,
+    gid: Uuid,
+) -> Result<DynResponse<Vec<Audit>>, ErrorResponse> {
+    Ok(DynResponse::new(db.as_ref().audits(gid).await?))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![retry, force_abort, mark_succeed, audits]
+}
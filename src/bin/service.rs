@@ -11,14 +11,17 @@ use rocket::response::Response;
 use rocket::serde::Serialize;
 use rocket::tokio::time::Instant;
 use rocket::Data;
-use tracing::{error, span};
+use tracing::{error, info, span};
 // use tracing_futures::WithSubscriber;
 use tracing_futures::Instrument;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::prelude::*;
 
 use luwu::config::{Config, CONFIG};
+use luwu::cron::Cron;
 use luwu::database::DatabaseManager;
+use luwu::http;
+use luwu::migrations;
 use luwu::responder::DynResponse;
 
 #[cfg(feature = "telemetry")]
@@ -110,23 +113,55 @@ impl Fairing for RequestTimer {
     }
 }
 
-#[launch]
-fn rocket() -> _ {
-    enable_tracing();
-    let figment = Figment::from(rocket::Config::default())
+fn figment() -> Figment {
+    Figment::from(rocket::Config::default())
         .merge(Serialized::defaults(Config::default()))
         .merge(Toml::file("luwu.toml").nested())
         .merge(Env::prefixed("LUWU_").global())
-        .select(Profile::from_env_or("LUWU_PROFILE", "default"));
+        .select(Profile::from_env_or("LUWU_PROFILE", "default"))
+}
+
+fn rocket(figment: Figment) -> rocket::Rocket<rocket::Build> {
     let routes = luwu::routes::routes();
     rocket::custom(figment)
-        .mount("/", routes![index])
+        .mount("/", routes![index, luwu::metrics::metrics])
         .mount("/api", routes)
+        .mount("/admin", luwu::admin::routes())
         .attach(AdHoc::config::<Config>())
         .attach(RequestTimer)
         .attach(DatabaseManager)
+        .attach(Cron)
         .attach(AdHoc::on_ignite("Saving config", |rocket| async move {
-            CONFIG.set(rocket.state::<Config>().unwrap().clone()).unwrap();
+            let config = rocket.state::<Config>().unwrap().clone();
+            config.validate().expect("Invalid configuration.");
+            http::init(&config).expect("Invalid http client configuration.");
+            CONFIG.set(config).unwrap();
             rocket
         }))
 }
+
+/// Apply migrations only, without launching the service.
+async fn migrate(figment: Figment) {
+    let config: Config = figment.extract().expect("Invalid configuration.");
+    match migrations::run(config.database_url()).await {
+        Ok(_) => info!("migrations applied."),
+        Err(err) => {
+            error!("migrations failed: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    enable_tracing();
+    let figment = figment();
+    match std::env::args().nth(1).as_deref() {
+        Some("migrate") => migrate(figment).await,
+        _ => {
+            if let Err(err) = rocket(figment).launch().await {
+                error!("launch failed: {}", err);
+            }
+        }
+    }
+}
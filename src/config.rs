@@ -1,17 +1,71 @@
+use std::collections::{BTreeMap, HashMap};
+
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 
+use crate::errors;
+
+/// Credential used to call RMs, branches refer to it by name so secrets stay in config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Credential {
+    // 以 `Authorization: Bearer <token>` 发送
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Credential {
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub delay: i64, // 单位秒 当事务等待这个时间之后，还没有变化，则进行一轮处理，包括prepared中的任务和committed的任务
+    pub max_delay: i64, // 单位秒 重试间隔加倍的上限
     pub database_url: String,
+    pub cron_interval: i64, // 单位秒 定时任务没有捞到到期事务时的休眠间隔
+    pub cron_concurrency: usize, // 定时任务同时处理的事务数上限，慢的 RM 不会阻塞其他事务的恢复
+    pub lease: i64, // 单位秒 实例处理事务时持有的租约时长，过期后其他实例可以接管
+    pub max_back_check_age: i64, // 单位秒 事务消息创建后超过这个时间仍回查不到结果，则标记为失败
+    pub timeout_to_fail: i64, // 单位秒 没有设置 timeout_to_fail 的 prepared 事务超过这个时间仍未提交，则回滚
+    pub connect_timeout: i64, // 单位秒 调用 RM 时建立连接的超时时间
+    pub request_timeout: i64, // 单位秒 调用 RM 的超时时间，分支可以单独设置
+    pub pool_max_idle_per_host: usize, // 每个 RM 保留的空闲连接数
+    #[serde(default)]
+    pub proxy: Option<String>, // 调用 RM 时使用的代理
+    #[serde(default)]
+    pub tls_roots: Vec<String>, // 额外信任的 PEM 格式根证书文件
+    #[serde(default)]
+    pub credentials: HashMap<String, Credential>, // 分支通过名字引用的凭证
+    #[serde(default)]
+    pub admin_tokens: HashMap<String, String>, // 管理接口的操作人及其 token，为空时管理接口不可用
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             delay: 10,
+            max_delay: 3600,
             database_url: String::new(),
+            cron_interval: 10,
+            cron_concurrency: 8,
+            lease: 30,
+            max_back_check_age: 86400,
+            timeout_to_fail: 60,
+            connect_timeout: 5,
+            request_timeout: 30,
+            pool_max_idle_per_host: 32,
+            proxy: None,
+            tls_roots: Vec::new(),
+            credentials: HashMap::new(),
+            admin_tokens: HashMap::new(),
         }
     }
 }
@@ -24,6 +78,84 @@ impl Config {
     pub fn delay(&self) -> i64 {
         self.delay
     }
+
+    pub fn max_delay(&self) -> i64 {
+        self.max_delay
+    }
+
+    pub fn cron_interval(&self) -> i64 {
+        self.cron_interval
+    }
+
+    pub fn cron_concurrency(&self) -> usize {
+        self.cron_concurrency
+    }
+
+    pub fn lease(&self) -> i64 {
+        self.lease
+    }
+
+    pub fn max_back_check_age(&self) -> i64 {
+        self.max_back_check_age
+    }
+
+    pub fn timeout_to_fail(&self) -> i64 {
+        self.timeout_to_fail
+    }
+
+    pub fn connect_timeout(&self) -> i64 {
+        self.connect_timeout
+    }
+
+    pub fn request_timeout(&self) -> i64 {
+        self.request_timeout
+    }
+
+    pub fn pool_max_idle_per_host(&self) -> usize {
+        self.pool_max_idle_per_host
+    }
+
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+
+    pub fn tls_roots(&self) -> &[String] {
+        &self.tls_roots
+    }
+
+    pub fn credential(&self, name: &str) -> Option<&Credential> {
+        self.credentials.get(name)
+    }
+
+    /// Check timeouts before serving, a call to RM must end before the lease of
+    /// the transaction expires, otherwise another instance may take it over.
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        let invalid = |msg: String| Err(errors::Error::InvalidConfig(msg));
+        if self.lease <= 0 {
+            return invalid(format!("lease must be positive, but {} found.", self.lease));
+        }
+        if self.connect_timeout <= 0 || self.request_timeout <= 0 {
+            return invalid(format!(
+                "connect_timeout and request_timeout must be positive, but {} and {} found.",
+                self.connect_timeout, self.request_timeout
+            ));
+        }
+        if self.request_timeout >= self.lease {
+            return invalid(format!(
+                "request_timeout {} must be shorter than the lease {}.",
+                self.request_timeout, self.lease
+            ));
+        }
+        Ok(())
+    }
+
+    /// The operator who owns the admin token.
+    pub fn operator(&self, token: &str) -> Option<&str> {
+        self.admin_tokens
+            .iter()
+            .find(|(_, admin_token)| !admin_token.is_empty() && admin_token.as_str() == token)
+            .map(|(operator, _)| operator.as_str())
+    }
 }
 
 pub static CONFIG: OnceCell<Config> = OnceCell::new();
@@ -1,65 +1,94 @@
-use rand::prelude::*;
-
-use std::thread;
+use std::sync::Arc;
 use std::time::Duration;
 
-/// Cron expired trans. use expire_in as expire time
-fn once(expire_after: Duration) -> bool {
-    if let Ok(trans) = lock(expire_after) {
-        // defer WaitTransProcessed(trans.Gid);
-        trans.process(dbGet());
-        return true;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio;
+use rocket::tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, error};
+
+use crate::config::Config;
+use crate::database;
+use crate::errors;
+use crate::models::transaction::Transaction;
+use crate::store::Store;
+
+/// 后台定时任务，负责把 `scheduled_at` 已到期但仍未完成的事务捞出来重新处理。
+/// 只要 TM 或 RM 崩溃导致 `process` 中断，事务最终都会被这里接管。
+pub struct Cron;
+
+#[rocket::async_trait]
+impl Fairing for Cron {
+    fn info(&self) -> Info {
+        Info {
+            name: "Cron for expired transactions.",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) {
+        let config = rocket.state::<Config>().unwrap().clone();
+        tokio::task::spawn(async move { expired(config, -1).await });
     }
-    return false;
 }
 
-// Cron expired trans, num == -1 indicate for ever
-fn expired(mut num: isize) {
-    loop {
-        if num == 0 {
-            break;
+/// Cron expired trans, num == -1 indicate for ever
+async fn expired(config: Config, mut num: isize) {
+    // 最多同时处理 cron_concurrency 个事务，一个卡在慢 RM 上的事务不影响其他事务
+    let permits = Arc::new(Semaphore::new(config.cron_concurrency().max(1)));
+    while num != 0 {
+        let permit = permits.clone().acquire_owned().await.expect("cron semaphore closed");
+        let processed = match database::store() {
+            Some(store) => once(store, permit).await,
+            None => Ok(false),
+        };
+        match processed {
+            Ok(true) => {}
+            Ok(false) => sleep(&config).await,
+            Err(err) => {
+                error!("cron transaction failed: {}", err);
+                sleep(&config).await;
+            }
         }
-        if once(Duration::new(0, 0)) {
-            sleep();
+        if num > 0 {
+            num -= 1;
         }
     }
 }
 
-struct Database;
-
-fn database() -> Database {
-    Database
-}
-
-fn lock(expire_after: Duration) -> Transaction {
-    let mut trans = Transaction::new();
-    let owner = trans.gid;
-    let db = database();
-    // 这里next_cron_time需要限定范围，否则数据量累计之后，会导致查询变慢
-    // 限定update_time < now - 3，否则会出现刚被这个应用取出，又被另一个取出
-    // dbr := db.Must().Model(&trans).
-    // Where("next_cron_time < date_add(now(), interval ? second) and next_cron_time > date_add(now(), interval -3600 second) and update_time < date_add(now(), interval ? second) and status in ('prepared', 'aborting', 'submitted')", int(expireIn/time.Second), -3+int(expireIn/time.Second)).
-    // Limit(1).Update("owner", owner)
-    // if dbr.RowsAffected == 0 {
-    // 	return nil
-    // }
-    // dbr = db.Must().Where("owner=?", owner).Find(&trans)
-    // updates := trans.setNextCron(trans.NextCronInterval * 2) // 下次被cron的间隔加倍
-    // db.Must().Model(&trans).Select(updates).Updates(&trans)
-    trans
+/// Claim one expired transaction and process it in background, holding `permit`
+/// until it is done, return false when there is nothing to do.
+async fn once(db: Arc<dyn Store>, permit: OwnedSemaphorePermit) -> Result<bool, errors::Error> {
+    let mut tx = match Transaction::lock_expired(db.as_ref()).await? {
+        Some(tx) => tx,
+        None => return Ok(false),
+    };
+    debug!("cron picked up transaction: {}", tx.gid());
+    tokio::task::spawn(async move {
+        if let Err(err) = process(db.as_ref(), &mut tx).await {
+            error!("cron transaction {} failed: {}", tx.gid(), err);
+        }
+        drop(permit);
+    });
+    Ok(true)
 }
 
-/*
-func handlePanic() {
-    if err := recover(); err != nil {
-        logrus.Errorf("\x1b[31m\n----panic %s handlered\x1b[0m\n%s", err.(error).Error(), string(debug.Stack()))
-    }
+async fn process(db: &dyn Store, tx: &mut Transaction) -> Result<(), errors::Error> {
+    let failed = match tx.process(db).await {
+        Ok(()) => false,
+        // 已经被其他实例接管，不能再改动调度
+        Err(err @ errors::Error::LeaseLost(_)) => return Err(err),
+        Err(err) => {
+            error!("processing transaction {} failed: {}", tx.gid(), err);
+            true
+        }
+    };
+    tx.reschedule(db, failed).await?;
+    tx.release_lease(db).await
 }
-*/
 
-fn sleep() {
-    let delta = 3f32.min(config.cron_interval as f32);
-    let interval =
-        Duration::from_secs_f32(config.cron_interval as f32 - rand::random::<f32>() * delta);
-    thread.sleep(interval);
+async fn sleep(config: &Config) {
+    let interval = config.cron_interval().max(1) as f32;
+    let delta = interval.min(3f32);
+    let interval = Duration::from_secs_f32(interval - rand::random::<f32>() * delta);
+    tokio::time::sleep(interval).await;
 }
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use once_cell::sync::OnceCell;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Status;
@@ -5,8 +7,10 @@ use rocket::request::{self, FromRequest, Request};
 
 use crate::config::Config;
 use crate::errors;
+use crate::migrations;
+use crate::store::{SqlStore, Store};
 
-static POOL: OnceCell<quaint::pooled::Quaint> = OnceCell::new();
+static STORE: OnceCell<Arc<dyn Store>> = OnceCell::new();
 
 pub struct DatabaseManager;
 
@@ -20,33 +24,50 @@ impl Fairing for DatabaseManager {
     }
 
     async fn on_liftoff(&self, rocket: &rocket::Rocket<rocket::Orbit>) {
+        if STORE.get().is_some() {
+            // 已经通过 set_store 指定了存储
+            return;
+        }
         let config = rocket.state::<Config>().unwrap();
-        let mut builder = quaint::pooled::Quaint::builder(config.database_url()).expect("Can not connect to database.");
-        builder.test_on_check_out(true);
-        POOL.set(builder.build()).ok();
+        migrations::run(config.database_url())
+            .await
+            .expect("Can not apply migrations.");
+        let store = SqlStore::connect(config.database_url()).expect("Can not connect to database.");
+        STORE.set(Arc::new(store)).ok();
     }
 }
 
-pub type Conn = quaint::pooled::PooledConnection;
+/// Store of transactions, available after liftoff.
+pub fn store() -> Option<Arc<dyn Store>> {
+    STORE.get().cloned()
+}
+
+/// Use `store` instead of the configured database, it only works before liftoff.
+pub fn set_store(store: Arc<dyn Store>) -> bool {
+    STORE.set(store).is_ok()
+}
+
+pub struct DB(Arc<dyn Store>);
 
-#[derive(Deref, DerefMut, AsRef, AsMut, Into)]
-#[into(owned, ref, ref_mut)]
-pub struct DB(Conn);
+impl AsRef<dyn Store> for DB {
+    fn as_ref(&self) -> &(dyn Store + 'static) {
+        self.0.as_ref()
+    }
+}
+
+impl From<DB> for Arc<dyn Store> {
+    fn from(db: DB) -> Arc<dyn Store> {
+        db.0
+    }
+}
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for DB {
     type Error = errors::Error;
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let outcome = request.guard::<&rocket::State<DatabaseManager>>().await;
-        match outcome {
-            request::Outcome::Success(_) => match POOL.get().unwrap().check_out().await {
-                Ok(conn) => request::Outcome::Success(DB(conn)),
-                Err(err) => request::Outcome::Failure((Status::ServiceUnavailable, err.into())),
-            },
-            request::Outcome::Forward(forward) => request::Outcome::Forward(forward),
-            request::Outcome::Failure((status, _)) => {
-                request::Outcome::Failure((status, errors::Error::DBNotAvailable))
-            }
+    async fn from_request(_request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match store() {
+            Some(store) => request::Outcome::Success(DB(store)),
+            None => request::Outcome::Failure((Status::ServiceUnavailable, errors::Error::DBNotAvailable)),
         }
     }
 }
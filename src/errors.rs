@@ -11,6 +11,19 @@ pub enum Error {
     HttpError(#[from] reqwest::Error),
     #[error("Database execute error {0}")]
     DBError(#[from] quaint::error::Error),
+    #[error("Database migration error {0}")]
+    MigrationError(#[from] refinery::Error),
+    #[cfg(feature = "postgresql")]
+    #[error("Database connection error {0}")]
+    PgError(#[from] tokio_postgres::Error),
+    #[cfg(feature = "mysql")]
+    #[error("Database connection error {0}")]
+    MysqlError(#[from] mysql_async::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("Database connection error {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("Database `{0}` is not supported, enable its feature: `postgresql`, `mysql` or `sqlite`.")]
+    UnsupportedDatabase(String),
     #[error("Database dose not configured.")]
     DBNotAvailable,
     #[error(
@@ -21,6 +34,30 @@ pub enum Error {
     UnexpectedType(String, String, String),
     #[error("Can not submit transaction({0}) from {1}.")]
     CannotSubmitTransaction(Gid, String),
+    #[error("Transaction({0}) is no longer {1}, its state was changed concurrently.")]
+    StateChanged(Gid, String),
+    #[error("Lease of transaction({0}) was taken by someone else, processing is stopped.")]
+    LeaseLost(Gid),
+    #[error("Transaction({0}) is {1}, branch registering is denied.")]
+    BranchRegisterDenied(Gid, String),
+    #[error("Invalid transaction definition: {0}")]
+    InvalidDefinition(String),
+    #[error("Unexpected response from {0}: {1}, will be retried.")]
+    UnexpectedResponse(String, String),
+    #[error("Can not {0} transaction({1}) which is {2}.")]
+    AdminActionDenied(String, Gid, String),
+    #[error("A reason is required to {0} a transaction.")]
+    ReasonRequired(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Transaction({0}) is not found.")]
+    TransactionNotFound(Gid),
+    #[error("Column {0} can not be mapped: {1}")]
+    InvalidColumn(&'static str, String),
+    #[error("Invalid configuration: {0}")]
+    InvalidConfig(String),
+    #[error("Can not load tls root {0}: {1}")]
+    TlsRootError(String, std::io::Error),
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -0,0 +1,38 @@
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use reqwest::{Certificate, Client, Proxy};
+
+use crate::config::Config;
+use crate::errors;
+
+/// 所有调用 RM 的请求共用一个连接池
+static CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// Build the shared client from `config`, it should be called once before serving.
+pub fn init(config: &Config) -> Result<(), errors::Error> {
+    let client = build(config)?;
+    // 重复初始化时保留第一个
+    let _ = CLIENT.set(client);
+    Ok(())
+}
+
+/// The shared client, falls back to the default configuration if not initialized.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| build(&Config::default()).expect("default http client"))
+}
+
+fn build(config: &Config) -> Result<Client, errors::Error> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(config.connect_timeout().max(1) as u64))
+        .timeout(Duration::from_secs(config.request_timeout().max(1) as u64))
+        .pool_max_idle_per_host(config.pool_max_idle_per_host());
+    if let Some(proxy) = config.proxy() {
+        builder = builder.proxy(Proxy::all(proxy)?);
+    }
+    for path in config.tls_roots() {
+        let pem = std::fs::read(path).map_err(|err| errors::Error::TlsRootError(path.clone(), err))?;
+        builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+    }
+    Ok(builder.build()?)
+}
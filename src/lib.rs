@@ -1,15 +1,16 @@
 #[macro_use]
 extern crate rocket;
-#[macro_use]
-extern crate derive_more;
 
-// mod cron;
+pub mod admin;
 pub mod config;
+pub mod cron;
 pub mod database;
 pub mod errors;
+pub mod http;
+pub mod metrics;
+pub mod migrations;
 pub mod models;
+pub mod processors;
 pub mod responder;
 pub mod routes;
-
-mod processors;
-// mod migrations;
+pub mod store;
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tracing::error;
+
+use crate::database;
+use crate::models::transaction::State;
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).unwrap();
+    collector
+}
+
+static CREATED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luwu_transactions_created_total", "Transactions created.");
+    register(IntCounterVec::new(opts, &["processor"]).unwrap())
+});
+
+static SUCCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luwu_transactions_succeeded_total", "Transactions finished as succeed.");
+    register(IntCounterVec::new(opts, &["processor"]).unwrap())
+});
+
+static FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luwu_transactions_failed_total", "Transactions finished as failed.");
+    register(IntCounterVec::new(opts, &["processor"]).unwrap())
+});
+
+static RETRIES: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luwu_retries_total", "Transactions scheduled again after an uncertain result.");
+    register(IntCounterVec::new(opts, &["processor"]).unwrap())
+});
+
+static CALL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = HistogramOpts::new("luwu_branch_call_duration_seconds", "Latency of calls to RMs.");
+    register(HistogramVec::new(opts, &["host"]).unwrap())
+});
+
+static SCHEDULER_LAG: Lazy<Gauge> = Lazy::new(|| {
+    let opts = Opts::new(
+        "luwu_scheduler_lag_seconds",
+        "How long the oldest due transaction has been waiting to be processed.",
+    );
+    register(Gauge::with_opts(opts).unwrap())
+});
+
+static CONNECTIONS_IN_USE: Lazy<IntGauge> = Lazy::new(|| {
+    let opts = Opts::new("luwu_db_connections_in_use", "Database connections checked out of the pool.");
+    register(IntGauge::with_opts(opts).unwrap())
+});
+
+pub(crate) fn transaction_created(processor: &str) {
+    CREATED.with_label_values(&[processor]).inc();
+}
+
+pub(crate) fn transaction_finished(processor: &str, state: State) {
+    match state {
+        State::Succeed => SUCCEEDED.with_label_values(&[processor]).inc(),
+        State::Failed => FAILED.with_label_values(&[processor]).inc(),
+        _ => {}
+    }
+}
+
+pub(crate) fn retried(processor: &str) {
+    RETRIES.with_label_values(&[processor]).inc();
+}
+
+pub(crate) fn branch_called(host: &str, latency: Duration) {
+    CALL_DURATION.with_label_values(&[host]).observe(latency.as_secs_f64());
+}
+
+/// Counts a checked out connection until dropped.
+pub(crate) struct ConnectionInUse;
+
+impl ConnectionInUse {
+    pub(crate) fn new() -> ConnectionInUse {
+        CONNECTIONS_IN_USE.inc();
+        ConnectionInUse
+    }
+}
+
+impl Drop for ConnectionInUse {
+    fn drop(&mut self) {
+        CONNECTIONS_IN_USE.dec();
+    }
+}
+
+/// Metrics in the prometheus text format, the scheduler lag is measured on
+/// each scrape.
+#[get("/metrics")]
+pub async fn metrics() -> String {
+    if let Some(db) = database::store() {
+        match db.oldest_due().await {
+            Ok(Some(scheduled_at)) => {
+                let lag = Local::now() - scheduled_at;
+                SCHEDULER_LAG.set(lag.num_milliseconds().max(0) as f64 / 1000.0);
+            }
+            Ok(None) => SCHEDULER_LAG.set(0.0),
+            Err(err) => error!("measuring scheduler lag failed: {}", err),
+        }
+    }
+    // 确保没有发生过的指标也会输出
+    Lazy::force(&CONNECTIONS_IN_USE);
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
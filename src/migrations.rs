@@ -0,0 +1,77 @@
+use refinery::Report;
+#[cfg(feature = "postgresql")]
+use rocket::tokio;
+use tracing::info;
+#[cfg(feature = "postgresql")]
+use tracing::error;
+
+use crate::errors;
+use crate::store::Dialect;
+
+#[cfg(feature = "postgresql")]
+mod postgresql {
+    use refinery::embed_migrations;
+    embed_migrations!("migrations/postgresql");
+}
+
+#[cfg(feature = "mysql")]
+mod mysql {
+    use refinery::embed_migrations;
+    embed_migrations!("migrations/mysql");
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use refinery::embed_migrations;
+    embed_migrations!("migrations/sqlite");
+}
+
+/// Apply all embedded migrations of the database's dialect which have not been applied yet.
+pub async fn run(database_url: &str) -> Result<(), errors::Error> {
+    let report = match Dialect::from_url(database_url)? {
+        #[cfg(feature = "postgresql")]
+        Dialect::Postgresql => run_postgresql(database_url).await?,
+        #[cfg(feature = "mysql")]
+        Dialect::Mysql => run_mysql(database_url).await?,
+        #[cfg(feature = "sqlite")]
+        Dialect::Sqlite => run_sqlite(database_url)?,
+        #[allow(unreachable_patterns)]
+        dialect => return Err(errors::Error::UnsupportedDatabase(format!("{:?}", dialect))),
+    };
+    for migration in report.applied_migrations() {
+        info!("applied migration {}", migration);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "postgresql")]
+async fn run_postgresql(database_url: &str) -> Result<Report, errors::Error> {
+    let (mut client, connection) = tokio_postgres::connect(database_url, tokio_postgres::NoTls).await?;
+    tokio::task::spawn(async move {
+        if let Err(err) = connection.await {
+            error!("migration connection error: {}", err);
+        }
+    });
+    Ok(postgresql::migrations::runner().run_async(&mut client).await?)
+}
+
+#[cfg(feature = "mysql")]
+async fn run_mysql(database_url: &str) -> Result<Report, errors::Error> {
+    let mut pool = mysql_async::Pool::new(database_url);
+    let report = mysql::migrations::runner().run_async(&mut pool).await?;
+    pool.disconnect().await?;
+    Ok(report)
+}
+
+#[cfg(feature = "sqlite")]
+fn run_sqlite(database_url: &str) -> Result<Report, errors::Error> {
+    // quaint 使用 `file:path?params` 的形式，rusqlite 只需要路径
+    let path = database_url
+        .splitn(2, ':')
+        .nth(1)
+        .unwrap_or_default()
+        .trim_start_matches("//");
+    let path = path.split('?').next().unwrap_or_default();
+    let mut conn = rusqlite::Connection::open(path)?;
+    Ok(sqlite::migrations::runner().run(&mut conn)?)
+}
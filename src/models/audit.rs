@@ -0,0 +1,76 @@
+use chrono::prelude::*;
+use quaint::connector::ResultRow;
+use quaint::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+use crate::models::mapping::{self, datetime_value, Table};
+use crate::models::transaction::Gid;
+
+/// What an operator did to a transaction through the admin routes, and why.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Audit {
+    gid: Gid,
+    operator: String,
+    action: String,
+    reason: String,
+    created_at: DateTime<Local>,
+}
+
+impl Audit {
+    pub fn new(gid: Gid, operator: String, action: String, reason: String) -> Audit {
+        Audit {
+            gid,
+            operator,
+            action,
+            reason,
+            created_at: Local::now(),
+        }
+    }
+
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+
+    pub fn action(&self) -> &str {
+        &self.action
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl Table for Audit {
+    fn tablename() -> &'static str {
+        "tx_audits"
+    }
+
+    fn columns() -> Vec<&'static str> {
+        vec!["gid", "operator", "action", "reason", "created_at"]
+    }
+
+    fn values(&self) -> Vec<Value<'static>> {
+        vec![
+            Value::from(self.gid),
+            Value::from(self.operator.clone()),
+            Value::from(self.action.clone()),
+            Value::from(self.reason.clone()),
+            datetime_value(Some(&self.created_at)),
+        ]
+    }
+
+    fn from_row(row: &ResultRow) -> Result<Audit, errors::Error> {
+        Ok(Audit {
+            gid: mapping::uuid(row, "gid")?,
+            operator: mapping::text(row, "operator")?,
+            action: mapping::text(row, "action")?,
+            reason: mapping::text(row, "reason")?,
+            created_at: mapping::datetime(row, "created_at")?,
+        })
+    }
+}
@@ -0,0 +1,183 @@
+use chrono::prelude::*;
+use quaint::connector::ResultRow;
+use quaint::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::errors;
+use crate::models::mapping::{self, datetime_value, Table};
+use crate::models::transaction::{Gid, State, TransactionBranch};
+
+/// 记录的 RM 返回内容最多保留的字节数
+const MAX_BODY_LEN: usize = 1024;
+
+/// Something that happened to a transaction, events are only appended.
+///
+/// `kind` is one of `state`, a state transition of the transaction or the
+/// branch, `call`, a request to RM, and `retry`, when the transaction is
+/// scheduled again after an uncertain result.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+    gid: Gid,
+    kind: String,
+    branch_id: Option<Gid>,
+    branch_type: Option<String>,
+    state: Option<State>,
+    url: Option<String>,
+    status: Option<i64>,
+    // 单位毫秒
+    latency: Option<i64>,
+    // 单位秒
+    delay: Option<i64>,
+    detail: Option<String>,
+    created_at: DateTime<Local>,
+}
+
+fn truncate(body: &str) -> String {
+    if body.len() <= MAX_BODY_LEN {
+        return body.to_string();
+    }
+    let mut end = MAX_BODY_LEN;
+    while !body.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &body[..end])
+}
+
+impl Event {
+    fn new(gid: Gid, kind: &str) -> Event {
+        Event {
+            gid,
+            kind: kind.to_string(),
+            branch_id: None,
+            branch_type: None,
+            state: None,
+            url: None,
+            status: None,
+            latency: None,
+            delay: None,
+            detail: None,
+            created_at: Local::now(),
+        }
+    }
+
+    /// The transaction, or the branch with `with_branch`, moved to `state`.
+    pub fn transition(gid: Gid, state: State, reason: Option<&str>) -> Event {
+        Event {
+            state: Some(state),
+            detail: reason.map(str::to_string),
+            ..Event::new(gid, "state")
+        }
+    }
+
+    /// A request to `url`, `status` is empty if no response was received.
+    pub fn call(gid: Gid, url: &str, status: Option<u16>, latency: i64, body: &str) -> Event {
+        Event {
+            url: Some(url.to_string()),
+            status: status.map(i64::from),
+            latency: Some(latency),
+            detail: Some(truncate(body)),
+            ..Event::new(gid, "call")
+        }
+    }
+
+    /// The transaction will be processed again after `delay` seconds.
+    pub fn retry(gid: Gid, delay: i64, detail: String) -> Event {
+        Event {
+            delay: Some(delay),
+            detail: Some(detail),
+            ..Event::new(gid, "retry")
+        }
+    }
+
+    pub fn with_branch(mut self, branch: &TransactionBranch) -> Event {
+        self.branch_id = Some(branch.branch_id());
+        self.branch_type = Some(branch.r#type().to_string());
+        self
+    }
+
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn branch_type(&self) -> Option<&str> {
+        self.branch_type.as_deref()
+    }
+
+    pub fn state(&self) -> Option<State> {
+        self.state
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn status(&self) -> Option<i64> {
+        self.status
+    }
+
+    pub fn delay(&self) -> Option<i64> {
+        self.delay
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+}
+
+impl Table for Event {
+    fn tablename() -> &'static str {
+        "tx_events"
+    }
+
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "gid",
+            "kind",
+            "branch_id",
+            "branch_type",
+            "state",
+            "url",
+            "status",
+            "latency",
+            "delay",
+            "detail",
+            "created_at",
+        ]
+    }
+
+    fn values(&self) -> Vec<Value<'static>> {
+        vec![
+            Value::from(self.gid),
+            Value::from(self.kind.clone()),
+            Value::Uuid(self.branch_id),
+            Value::Text(self.branch_type.clone().map(Into::into)),
+            Value::Integer(self.state.map(|state| state as i64)),
+            Value::Text(self.url.clone().map(Into::into)),
+            Value::Integer(self.status),
+            Value::Integer(self.latency),
+            Value::Integer(self.delay),
+            Value::Text(self.detail.clone().map(Into::into)),
+            datetime_value(Some(&self.created_at)),
+        ]
+    }
+
+    fn from_row(row: &ResultRow) -> Result<Event, errors::Error> {
+        Ok(Event {
+            gid: mapping::uuid(row, "gid")?,
+            kind: mapping::text(row, "kind")?,
+            branch_id: mapping::opt_uuid(row, "branch_id")?,
+            branch_type: mapping::opt_text(row, "branch_type")?,
+            state: mapping::opt_state(row, "state")?,
+            url: mapping::opt_text(row, "url")?,
+            status: mapping::opt_integer(row, "status")?,
+            latency: mapping::opt_integer(row, "latency")?,
+            delay: mapping::opt_integer(row, "delay")?,
+            detail: mapping::opt_text(row, "detail")?,
+            created_at: mapping::datetime(row, "created_at")?,
+        })
+    }
+}
@@ -0,0 +1,148 @@
+use chrono::prelude::*;
+use quaint::connector::{ResultRow, ResultSet};
+use quaint::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors;
+use crate::models::transaction::{Gid, State};
+
+/// The mapping between a model and the columns of its table.
+///
+/// Models are written by `columns` and `values` and read back by `from_row`, so
+/// what is saved is exactly what is loaded.
+pub trait Table: Sized {
+    fn tablename() -> &'static str;
+
+    /// Columns written on insertion, in the order of `values`.
+    fn columns() -> Vec<&'static str>;
+
+    fn values(&self) -> Vec<Value<'static>>;
+
+    fn from_row(row: &ResultRow) -> Result<Self, errors::Error>;
+
+    fn from_rows(rows: ResultSet) -> Result<Vec<Self>, errors::Error> {
+        rows.into_iter().map(|row| Self::from_row(&row)).collect()
+    }
+
+    fn from_first(rows: ResultSet) -> Result<Option<Self>, errors::Error> {
+        rows.into_iter().next().map(|row| Self::from_row(&row)).transpose()
+    }
+
+    /// Values of the given columns, for partial updates.
+    fn values_of(&self, names: &[&'static str]) -> Vec<(&'static str, Value<'static>)> {
+        Self::columns()
+            .into_iter()
+            .zip(self.values())
+            .filter(|(name, _)| names.contains(name))
+            .collect()
+    }
+}
+
+fn column<'a>(row: &'a ResultRow, name: &'static str) -> Result<&'a Value<'static>, errors::Error> {
+    row.get(name)
+        .ok_or(errors::Error::InvalidColumn(name, "missing".to_string()))
+}
+
+fn invalid(name: &'static str, value: &Value) -> errors::Error {
+    errors::Error::InvalidColumn(name, format!("unexpected value {:?}", value))
+}
+
+// MySQL 与 SQLite 没有 uuid 类型，读出的是文本
+fn as_uuid(value: &Value) -> Option<Gid> {
+    value
+        .as_uuid()
+        .or_else(|| value.as_str().and_then(|s| Gid::parse_str(s).ok()))
+}
+
+pub(crate) fn uuid(row: &ResultRow, name: &'static str) -> Result<Gid, errors::Error> {
+    let value = column(row, name)?;
+    as_uuid(value).ok_or_else(|| invalid(name, value))
+}
+
+pub(crate) fn opt_uuid(row: &ResultRow, name: &'static str) -> Result<Option<Gid>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        value => as_uuid(value).map(Some).ok_or_else(|| invalid(name, value)),
+    }
+}
+
+pub(crate) fn integer(row: &ResultRow, name: &'static str) -> Result<i64, errors::Error> {
+    let value = column(row, name)?;
+    value.as_i64().ok_or_else(|| invalid(name, value))
+}
+
+pub(crate) fn opt_integer(row: &ResultRow, name: &'static str) -> Result<Option<i64>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        value => value.as_i64().map(Some).ok_or_else(|| invalid(name, value)),
+    }
+}
+
+pub(crate) fn text(row: &ResultRow, name: &'static str) -> Result<String, errors::Error> {
+    let value = column(row, name)?;
+    value.as_str().map(str::to_string).ok_or_else(|| invalid(name, value))
+}
+
+pub(crate) fn opt_text(row: &ResultRow, name: &'static str) -> Result<Option<String>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        value => value.as_str().map(|s| Some(s.to_string())).ok_or_else(|| invalid(name, value)),
+    }
+}
+
+pub(crate) fn state(row: &ResultRow, name: &'static str) -> Result<State, errors::Error> {
+    let value = column(row, name)?;
+    value.as_i64().and_then(State::from_i64).ok_or_else(|| invalid(name, value))
+}
+
+pub(crate) fn opt_state(row: &ResultRow, name: &'static str) -> Result<Option<State>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        _ => state(row, name).map(Some),
+    }
+}
+
+pub(crate) fn datetime(row: &ResultRow, name: &'static str) -> Result<DateTime<Local>, errors::Error> {
+    let value = column(row, name)?;
+    value
+        .as_datetime()
+        .map(|at| at.with_timezone(&Local))
+        .ok_or_else(|| invalid(name, value))
+}
+
+pub(crate) fn opt_datetime(row: &ResultRow, name: &'static str) -> Result<Option<DateTime<Local>>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        value => value
+            .as_datetime()
+            .map(|at| Some(at.with_timezone(&Local)))
+            .ok_or_else(|| invalid(name, value)),
+    }
+}
+
+pub(crate) fn json<T: DeserializeOwned>(row: &ResultRow, name: &'static str) -> Result<T, errors::Error> {
+    let value = column(row, name)?;
+    let parsed = match (value.as_json(), value.as_str()) {
+        (Some(json), _) => serde_json::from_value(json.clone()),
+        // SQLite 中的 json 以文本保存
+        (None, Some(text)) => serde_json::from_str(text),
+        (None, None) => return Err(invalid(name, value)),
+    };
+    parsed.map_err(|err| errors::Error::InvalidColumn(name, err.to_string()))
+}
+
+pub(crate) fn opt_json<T: DeserializeOwned>(row: &ResultRow, name: &'static str) -> Result<Option<T>, errors::Error> {
+    match column(row, name)? {
+        value if value.is_null() => Ok(None),
+        _ => json(row, name).map(Some),
+    }
+}
+
+pub(crate) fn json_value<T: Serialize>(value: Option<&T>) -> Value<'static> {
+    Value::Json(value.map(|value| serde_json::to_value(value).unwrap()))
+}
+
+pub(crate) fn datetime_value(value: Option<&DateTime<Local>>) -> Value<'static> {
+    Value::DateTime(value.map(|at| at.with_timezone(&Utc)))
+}
@@ -1,3 +1,13 @@
+pub mod audit;
+pub mod event;
+pub mod mapping;
+pub mod options;
+pub mod retry;
 pub mod transaction;
 
+pub use audit::Audit;
+pub use event::Event;
+pub use mapping::Table;
+pub use options::RequestOptions;
+pub use retry::RetryPolicy;
 pub use transaction::{Transaction, TransactionBranch, TransactionCreation};
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+use crate::config::CONFIG;
+use crate::errors;
+
+/// Options of the requests luwu sends to a branch, overriding the global `Config`.
+///
+/// Options of a transaction apply to all its branches, a branch's own options take
+/// precedence over them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct RequestOptions {
+    // 单位秒 为空时使用 request_timeout
+    #[serde(default)]
+    timeout: Option<i64>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    // 引用配置中 `credentials` 的名字
+    #[serde(default)]
+    credential: Option<String>,
+}
+
+impl RequestOptions {
+    pub fn new() -> RequestOptions {
+        RequestOptions::default()
+    }
+
+    pub fn with_timeout(mut self, timeout: i64) -> RequestOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_header(mut self, name: String, value: String) -> RequestOptions {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_credential(mut self, credential: String) -> RequestOptions {
+        self.credential = Some(credential);
+        self
+    }
+
+    pub fn timeout(&self) -> Option<i64> {
+        self.timeout
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn credential(&self) -> Option<&str> {
+        self.credential.as_deref()
+    }
+
+    /// Check the timeout ends within the lease, the credential is configured and
+    /// the headers can be sent, so a request can not fail the same way on every retry.
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        let invalid = |msg: String| Err(errors::Error::InvalidDefinition(msg));
+        if let Some(timeout) = self.timeout {
+            if timeout <= 0 {
+                return invalid(format!("timeout must be positive, but {} found.", timeout));
+            }
+            // 超过租约的请求还未返回时，事务可能已被其他实例接管
+            if let Some(lease) = CONFIG.get().map(|config| config.lease()) {
+                if timeout >= lease {
+                    return invalid(format!("timeout {} must be shorter than the lease {}.", timeout, lease));
+                }
+            }
+        }
+        if let Some(name) = self.credential() {
+            if CONFIG.get().and_then(|config| config.credential(name)).is_none() {
+                return invalid(format!("credential `{}` is not configured.", name));
+            }
+        }
+        for (name, value) in self.headers.iter() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return invalid(format!("header name `{}` is invalid.", name));
+            }
+            if HeaderValue::from_str(value).is_err() {
+                return invalid(format!("value of header `{}` is invalid.", name));
+            }
+        }
+        Ok(())
+    }
+}
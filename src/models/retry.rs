@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::errors;
+
+/// How a transaction or a branch is retried when the result is uncertain.
+///
+/// Intervals are in seconds, the n-th retry waits
+/// `initial_interval * multiplier ^ (n - 1)`, capped by `max_interval`, then
+/// randomly scaled by `1 ± jitter`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RetryPolicy {
+    initial_interval: i64,
+    #[serde(default = "RetryPolicy::default_multiplier")]
+    multiplier: f64,
+    #[serde(default = "RetryPolicy::default_max_interval")]
+    max_interval: i64,
+    // 为空时不限次数
+    #[serde(default)]
+    max_attempts: Option<i64>,
+    #[serde(default)]
+    jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn new(initial_interval: i64, multiplier: f64, max_interval: i64) -> RetryPolicy {
+        RetryPolicy {
+            initial_interval,
+            multiplier,
+            max_interval,
+            max_attempts: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// The global policy configured by `delay` and `max_delay`.
+    pub fn from_config(config: &Config) -> RetryPolicy {
+        RetryPolicy::new(config.delay(), Self::default_multiplier(), config.max_delay())
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: i64) -> RetryPolicy {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> RetryPolicy {
+        self.jitter = jitter;
+        self
+    }
+
+    fn default_multiplier() -> f64 {
+        2.0
+    }
+
+    fn default_max_interval() -> i64 {
+        3600
+    }
+
+    pub fn initial_interval(&self) -> i64 {
+        self.initial_interval
+    }
+
+    pub fn max_attempts(&self) -> Option<i64> {
+        self.max_attempts
+    }
+
+    /// Check the policy can be used to compute intervals.
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        let invalid = |msg: String| Err(errors::Error::InvalidDefinition(msg));
+        if self.initial_interval <= 0 {
+            return invalid(format!("retry policy initial_interval must be positive, but {} found.", self.initial_interval));
+        }
+        if self.max_interval < self.initial_interval {
+            return invalid(format!(
+                "retry policy max_interval must be at least initial_interval {}, but {} found.",
+                self.initial_interval, self.max_interval
+            ));
+        }
+        if let Some(max_attempts) = self.max_attempts.filter(|&max_attempts| max_attempts <= 0) {
+            return invalid(format!("retry policy max_attempts must be positive, but {} found.", max_attempts));
+        }
+        if self.multiplier.is_nan() || self.multiplier < 1.0 {
+            return invalid(format!("retry policy multiplier must be at least 1, but {} found.", self.multiplier));
+        }
+        if !(0.0..1.0).contains(&self.jitter) {
+            return invalid(format!("retry policy jitter must be in [0, 1), but {} found.", self.jitter));
+        }
+        Ok(())
+    }
+
+    /// Whether `attempts` calls used up the attempts.
+    pub fn exhausted(&self, attempts: i64) -> bool {
+        self.max_attempts
+            .map(|max_attempts| attempts >= max_attempts)
+            .unwrap_or(false)
+    }
+
+    /// The interval after the previous one was `delay`.
+    pub fn next(&self, delay: i64) -> i64 {
+        let delay = (delay.max(self.initial_interval) as f64 * self.multiplier) as i64;
+        self.jittered(delay.min(self.max_interval))
+    }
+
+    /// The interval before the next call, after `attempts` calls.
+    pub fn backoff(&self, attempts: i64) -> i64 {
+        let exp = (attempts - 1).clamp(0, 63) as i32;
+        let delay = self.initial_interval as f64 * self.multiplier.powi(exp);
+        self.jittered((delay as i64).min(self.max_interval))
+    }
+
+    fn jittered(&self, delay: i64) -> i64 {
+        if self.jitter <= 0.0 {
+            return delay.max(1);
+        }
+        let factor = 1.0 + self.jitter * (rand::random::<f64>() * 2.0 - 1.0);
+        ((delay as f64 * factor) as i64).max(1)
+    }
+}
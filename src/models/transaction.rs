@@ -1,39 +1,238 @@
 use chrono::prelude::*;
 use chrono::Duration;
-use quaint::pooled::PooledConnection;
+use quaint::connector::ResultRow;
 use quaint::prelude::*;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, event, Level};
 
 use crate::config::CONFIG;
 use crate::errors;
+use crate::metrics;
+use crate::models::mapping::{self, datetime_value, json_value, Table};
+use crate::models::{Event, RequestOptions, RetryPolicy};
 
 use crate::processors::{Processor, ProcessorType};
+use crate::store::Store;
 
 pub type Gid = rocket::serde::uuid::Uuid;
 
-type Conn = PooledConnection;
-
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TransactionCreation {
+    // 为空时由服务端生成
+    #[serde(default)]
+    gid: Option<Gid>,
     r#type: ProcessorType,
+    #[serde(default)]
     payload: String,
+    #[serde(default)]
     query_prepared: String,
+    // 为空时使用全局配置
+    #[serde(default)]
+    retry_policy: Option<RetryPolicy>,
+    // 单位秒 创建后超过这个时间仍未提交，则自动回滚
+    #[serde(default)]
+    timeout_to_fail: Option<i64>,
+    // 所有分支共用的请求选项
+    #[serde(default)]
+    options: Option<RequestOptions>,
+}
+
+impl TransactionCreation {
+    pub fn new(r#type: ProcessorType, payload: String, query_prepared: String) -> TransactionCreation {
+        TransactionCreation {
+            gid: None,
+            r#type,
+            payload,
+            query_prepared,
+            retry_policy: None,
+            timeout_to_fail: None,
+            options: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: RequestOptions) -> TransactionCreation {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn with_timeout_to_fail(mut self, timeout_to_fail: i64) -> TransactionCreation {
+        self.timeout_to_fail = Some(timeout_to_fail);
+        self
+    }
+
+    pub fn with_gid(mut self, gid: Gid) -> TransactionCreation {
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> TransactionCreation {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn r#type(&self) -> &ProcessorType {
+        &self.r#type
+    }
+
+    /// Check the definition before anything is saved.
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        self.r#type.validate()?;
+        if let Some(policy) = self.retry_policy.as_ref() {
+            policy.validate()?;
+        }
+        if let Some(options) = self.options.as_ref() {
+            options.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// Registered by RM after its local `XA PREPARE` succeed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct XaBranchCreation {
+    branch_id: Gid,
+    commit_url: String,
+    rollback_url: String,
+    #[serde(default)]
+    payload: String,
+    #[serde(default)]
+    options: Option<RequestOptions>,
+}
+
+impl XaBranchCreation {
+    pub fn new(branch_id: Gid, commit_url: String, rollback_url: String, payload: String) -> XaBranchCreation {
+        XaBranchCreation {
+            branch_id,
+            commit_url,
+            rollback_url,
+            payload,
+            options: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: RequestOptions) -> XaBranchCreation {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        match self.options.as_ref() {
+            Some(options) => options.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// The rollback and commit branches of this registration.
+    pub fn into_branches(self, gid: Gid) -> Vec<TransactionBranch> {
+        let XaBranchCreation {
+            branch_id,
+            commit_url,
+            rollback_url,
+            payload,
+            options,
+        } = self;
+        vec![
+            TransactionBranch::new(gid, branch_id, "rollback".to_string(), State::Prepared, rollback_url, payload.clone()),
+            TransactionBranch::new(gid, branch_id, "commit".to_string(), State::Prepared, commit_url, payload),
+        ]
+        .into_iter()
+        .map(|branch| branch.with_options(options.clone()))
+        .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TCCBranchCreation {
+    state: State,
+    payload: String,
+    cancel_url: String,
+    confirm_url: String,
+    r#try_url: String,
+    #[serde(default)]
+    retry_policy: Option<RetryPolicy>,
+    #[serde(default)]
+    options: Option<RequestOptions>,
+}
+
+impl TCCBranchCreation {
+    pub fn new(payload: String, try_url: String, confirm_url: String, cancel_url: String) -> TCCBranchCreation {
+        TCCBranchCreation {
+            state: State::Prepared,
+            payload,
+            cancel_url,
+            confirm_url,
+            try_url,
+            retry_policy: None,
+            options: None,
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> TCCBranchCreation {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn with_options(mut self, options: RequestOptions) -> TCCBranchCreation {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        if let Some(policy) = self.retry_policy.as_ref() {
+            policy.validate()?;
+        }
+        match self.options.as_ref() {
+            Some(options) => options.validate(),
+            None => Ok(()),
+        }
+    }
+
+    /// The cancel, confirm and try branches of this registration.
+    pub fn into_branches(self, gid: Gid, branch_id: Gid) -> Vec<TransactionBranch> {
+        let TCCBranchCreation {
+            cancel_url,
+            confirm_url,
+            try_url,
+            state,
+            payload,
+            retry_policy,
+            options,
+        } = self;
+        vec![
+            TransactionBranch::new(gid, branch_id, "cancel".to_string(), state, cancel_url, payload.clone()),
+            TransactionBranch::new(gid, branch_id, "confirm".to_string(), state, confirm_url, payload.clone()),
+            TransactionBranch::new(gid, branch_id, "try".to_string(), state, try_url, payload),
+        ]
+        .into_iter()
+        .map(|branch| {
+            branch
+                .with_retry_policy(retry_policy.clone())
+                .with_options(options.clone())
+        })
+        .collect()
+    }
 }
 
 impl From<TransactionCreation> for Transaction {
     fn from(c: TransactionCreation) -> Self {
         Transaction {
-            gid: Gid::new_v4(),
+            gid: c.gid.unwrap_or_else(Gid::new_v4),
             state: State::Prepared,
             r#type: c.r#type,
             payload: c.payload,
             query_prepared: c.query_prepared,
+            retry_policy: c.retry_policy,
+            timeout_to_fail: c.timeout_to_fail,
+            options: c.options,
+            reason: None,
             committed_at: None,
             finished_at: None,
             rollbacked_at: None,
             scheduled_at: None,
             delay: -1,
+            owner: None,
+            lease_expires_at: None,
+            lease: None,
             created_at: Local::now(),
             last_modified: Local::now(),
         }
@@ -47,11 +246,25 @@ pub struct Transaction {
     r#type: ProcessorType,
     payload: String,
     query_prepared: String,
+    #[serde(default)]
+    retry_policy: Option<RetryPolicy>,
+    #[serde(default)]
+    timeout_to_fail: Option<i64>,
+    #[serde(default)]
+    options: Option<RequestOptions>,
+    // 进入 aborting 的原因
+    #[serde(default)]
+    reason: Option<String>,
     committed_at: Option<DateTime<Local>>,
     finished_at: Option<DateTime<Local>>,
     rollbacked_at: Option<DateTime<Local>>,
     delay: i64,
     scheduled_at: Option<DateTime<Local>>,
+    owner: Option<Gid>,
+    lease_expires_at: Option<DateTime<Local>>,
+    // 本次处理持有的租约令牌，每次获取租约都重新生成，不落库
+    #[serde(skip)]
+    lease: Option<Gid>,
     created_at: DateTime<Local>,
     last_modified: DateTime<Local>,
 }
@@ -76,10 +289,6 @@ impl Transaction {
     }
     */
 
-    pub fn tablename() -> &'static str {
-        "tx_transactions"
-    }
-
     pub fn gid(&self) -> uuid::Uuid {
         self.gid
     }
@@ -100,69 +309,168 @@ impl Transaction {
         &self.query_prepared
     }
 
+    pub fn delay(&self) -> i64 {
+        self.delay
+    }
+
+    pub fn options(&self) -> Option<&RequestOptions> {
+        self.options.as_ref()
+    }
+
+    pub fn timeout_to_fail(&self) -> Option<i64> {
+        self.timeout_to_fail
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub fn owner(&self) -> Option<Gid> {
+        self.owner
+    }
+
+    /// When a prepared transaction will be aborted, `timeout_to_fail` of the config
+    /// applies if it is not set. Messages are checked back instead and have none by default.
+    pub fn deadline(&self) -> Option<DateTime<Local>> {
+        let timeout = match (self.timeout_to_fail, &self.r#type) {
+            (Some(timeout), _) => timeout,
+            (None, ProcessorType::Message(_)) => return None,
+            (None, _) => CONFIG.get()?.timeout_to_fail(),
+        };
+        Some(self.created_at + Duration::seconds(timeout))
+    }
+
+    /// The policy of this transaction, or the global one.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self.retry_policy.as_ref() {
+            Some(retry_policy) => retry_policy.clone(),
+            None => RetryPolicy::from_config(CONFIG.get().unwrap()),
+        }
+    }
+
+    pub fn created_at(&self) -> &DateTime<Local> {
+        &self.created_at
+    }
+
+    pub fn last_modified(&self) -> &DateTime<Local> {
+        &self.last_modified
+    }
+
     pub fn submitted(&mut self) {
         self.state = State::Submitted;
     }
 
-    pub async fn branches(&self, db: &Conn) -> Result<Vec<TransactionBranch>, errors::Error> {
-        let branches = quaint::serde::from_rows(
-            db.select(
-                Select::from_table(TransactionBranch::tablename()).so_that("gid".equals(self.gid)),
-            )
-            .await?,
-        )?;
-        Ok(branches)
+    pub async fn branches(&self, db: &dyn Store) -> Result<Vec<TransactionBranch>, errors::Error> {
+        db.branches(self.gid).await
     }
 
     fn set_scheduled_at(&mut self, delay: i64) {
-        let config = CONFIG.get().unwrap();
         self.delay = delay;
-        let scheduled_at = Local::now() + Duration::seconds(config.delay);
+        let mut scheduled_at = Local::now() + Duration::seconds(delay);
+        if let (State::Prepared, Some(deadline)) = (self.state, self.deadline()) {
+            // 保证到期时会被 cron 捞到
+            scheduled_at = scheduled_at.min(deadline);
+        }
         self.scheduled_at.replace(scheduled_at);
     }
 
-    pub async fn touch(&mut self, db: &Conn, delay: i64) -> Result<(), errors::Error> {
+    pub async fn touch(&mut self, db: &dyn Store, delay: i64) -> Result<(), errors::Error> {
         event!(Level::TRACE, action = "touch transaction", gid = ?self.gid, state = "", branch = "");
+        if let Some(lease) = self.lease {
+            // 处理过程中的每次 touch 先续约，租约已被别人拿走时不再改动调度，停止处理
+            let lease_expires_at = Self::lease_expires_at();
+            if !db.acquire_lease(self.gid, lease, lease_expires_at).await? {
+                self.lease = None;
+                return Err(errors::Error::LeaseLost(self.gid));
+            }
+            self.lease_expires_at = Some(lease_expires_at);
+        }
         self.set_scheduled_at(delay);
-        let scheduled_at: DateTime<Utc> = self.scheduled_at.as_ref().unwrap().with_timezone(&Utc);
-        let x = Update::table(Self::tablename())
-            .set("scheduled_at", scheduled_at)
-            .set("delay", self.delay)
-            .so_that("gid".equals(self.gid));
-        db.update(x).await?;
-        Ok(())
+        db.touch(self).await
     }
 
-    pub async fn update_state(&mut self, db: &Conn, state: State) -> Result<(), errors::Error> {
-        event!(Level::TRACE, gid = ?self.gid, action= "change state", state= ?state, branch= "");
+    fn lease_expires_at() -> DateTime<Local> {
         let config = CONFIG.get().unwrap();
-        // let old = self.state;
-        self.set_scheduled_at(config.delay);
-        let scheduled_at: DateTime<Utc> = self.scheduled_at.as_ref().unwrap().with_timezone(&Utc);
-        let mut x = Update::table(Self::tablename())
-            .set("scheduled_at", scheduled_at)
-            .set("delay", self.delay)
-            .set("state", state)
-            .so_that("gid".equals(self.gid));
-        let now = Local::now();
-        match state {
-            State::Succeed => {
-                self.finished_at = Some(now);
-                let finished_at: DateTime<Utc> =
-                    self.finished_at.as_ref().unwrap().with_timezone(&Utc);
-                x = x.set("finished_at", finished_at);
-            }
-            State::Failed => {
-                self.rollbacked_at = Some(now);
-                let rollbacked_at: DateTime<Utc> =
-                    self.rollbacked_at.as_ref().unwrap().with_timezone(&Utc);
-                x = x.set("rollbacked_at", rollbacked_at);
+        Local::now() + Duration::seconds(config.lease())
+    }
+
+    /// Take the lease of this transaction, return false if it is held by
+    /// anyone else, this instance included, and has not expired yet.
+    pub async fn acquire_lease(&mut self, db: &dyn Store) -> Result<bool, errors::Error> {
+        let lease = Gid::new_v4();
+        let lease_expires_at = Self::lease_expires_at();
+        let acquired = db.acquire_lease(self.gid, lease, lease_expires_at).await?;
+        if acquired {
+            event!(Level::TRACE, gid = ?self.gid, action = "acquire lease", owner = ?lease);
+            self.owner = Some(lease);
+            self.lease = Some(lease);
+            self.lease_expires_at = Some(lease_expires_at);
+        }
+        Ok(acquired)
+    }
+
+    /// Give the lease back, so any instance can pick it up when it is due.
+    pub async fn release_lease(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
+        if let Some(lease) = self.lease.take() {
+            event!(Level::TRACE, gid = ?self.gid, action = "release lease", owner = ?lease);
+            db.release_lease(self.gid, lease).await?;
+        }
+        self.owner = None;
+        self.lease_expires_at = None;
+        Ok(())
+    }
+
+    pub async fn update_state(&mut self, db: &dyn Store, state: State) -> Result<(), errors::Error> {
+        self.update_state_with_reason(db, state, None).await
+    }
+
+    /// Abort the transaction, and record why.
+    pub async fn abort(&mut self, db: &dyn Store, reason: &str) -> Result<(), errors::Error> {
+        self.update_state_with_reason(db, State::Aborting, Some(reason)).await
+    }
+
+    /// Mark the transaction succeed without calling RMs, for transactions fixed by hand.
+    pub async fn mark_succeed(&mut self, db: &dyn Store, reason: &str) -> Result<(), errors::Error> {
+        self.update_state_with_reason(db, State::Succeed, Some(reason)).await
+    }
+
+    /// Give branches still prepared, whose results are uncertain or not tried yet,
+    /// their attempts back and make the transaction due now. Succeed and failed
+    /// branches are kept, a failure is definite and has been acted on.
+    pub async fn retry(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
+        for mut branch in self.branches(db).await? {
+            if branch.state() == State::Prepared && branch.attempts() > 0 {
+                branch.reset_attempts(db).await?;
             }
+        }
+        self.touch(db, 0).await
+    }
+
+    async fn update_state_with_reason(
+        &mut self,
+        db: &dyn Store,
+        state: State,
+        reason: Option<&str>,
+    ) -> Result<(), errors::Error> {
+        event!(Level::TRACE, gid = ?self.gid, action= "change state", state= ?state, branch= "", reason = ?reason);
+        let expected = self.state;
+        self.set_scheduled_at(self.retry_policy().initial_interval());
+        if let Some(reason) = reason {
+            self.reason = Some(reason.to_string());
+        }
+        match state {
+            State::Succeed => self.finished_at = Some(Local::now()),
+            State::Failed => self.rollbacked_at = Some(Local::now()),
             _ => {}
         }
-        db.update(x).await?;
         self.state = state;
-        Ok(())
+        self.last_modified = Local::now();
+        // 只有状态仍是读取时的状态才写入，避免覆盖其他实例的修改
+        if !db.update_state(self, expected).await? {
+            return Err(errors::Error::StateChanged(self.gid, expected.tag().to_string()));
+        }
+        metrics::transaction_finished(self.r#type.tag(), state);
+        db.insert_event(&Event::transition(self.gid, state, reason)).await
     }
 
     pub fn processor<'tx>(&'tx mut self) -> Box<dyn Processor<'tx> + 'tx> {
@@ -190,6 +498,28 @@ impl State {
             &State::Submitted => "submitted",
         }
     }
+
+    pub fn from_i64(state: i64) -> Option<State> {
+        match state {
+            1 => Some(State::Submitted),
+            2 => Some(State::Prepared),
+            3 => Some(State::Aborting),
+            4 => Some(State::Failed),
+            5 => Some(State::Succeed),
+            _ => None,
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<State> {
+        match tag {
+            "submitted" => Some(State::Submitted),
+            "prepared" => Some(State::Prepared),
+            "aborting" => Some(State::Aborting),
+            "failed" => Some(State::Failed),
+            "succeed" => Some(State::Succeed),
+            _ => None,
+        }
+    }
 }
 
 impl From<State> for Value<'static> {
@@ -207,6 +537,14 @@ pub struct TransactionBranch {
     branch_id: Gid,
     r#type: String,
     state: State,
+    // 为空时使用事务的重试策略
+    #[serde(default)]
+    retry_policy: Option<RetryPolicy>,
+    #[serde(default)]
+    attempts: i64,
+    // 为空时使用全局配置
+    #[serde(default)]
+    options: Option<RequestOptions>,
     finished_at: Option<DateTime<Local>>,
     rollbacked_at: Option<DateTime<Local>>,
     created_at: DateTime<Local>,
@@ -214,10 +552,6 @@ pub struct TransactionBranch {
 }
 
 impl TransactionBranch {
-    fn tablename() -> &'static str {
-        "tx_transaction_branches"
-    }
-
     pub fn new(
         gid: Gid,
         branch_id: uuid::Uuid,
@@ -233,6 +567,9 @@ impl TransactionBranch {
             r#type,
             url,
             payload,
+            retry_policy: None,
+            attempts: 0,
+            options: None,
             finished_at: None,
             rollbacked_at: None,
             created_at: Local::now(),
@@ -240,6 +577,39 @@ impl TransactionBranch {
         }
     }
 
+    pub fn with_retry_policy(mut self, retry_policy: Option<RetryPolicy>) -> TransactionBranch {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub fn with_options(mut self, options: Option<RequestOptions>) -> TransactionBranch {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> Option<&RequestOptions> {
+        self.options.as_ref()
+    }
+
+    pub fn attempts(&self) -> i64 {
+        self.attempts
+    }
+
+    pub async fn reset_attempts(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
+        self.attempts = 0;
+        db.update_branch(self).await
+    }
+
+    /// Count a call to RM.
+    pub async fn attempt(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
+        self.attempts += 1;
+        db.update_branch(self).await
+    }
+
     pub fn r#type(&self) -> &str {
         &self.r#type
     }
@@ -260,43 +630,73 @@ impl TransactionBranch {
         self.branch_id
     }
 
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
     pub fn with_type(&mut self, r#type: String) {
         self.r#type = r#type;
     }
 
-    pub async fn update_state(&mut self, db: &Conn, state: State) -> Result<(), errors::Error> {
+    /// Count the attempt, mark the branch succeed and save the branches it leads to atomically.
+    pub async fn advance(&mut self, db: &dyn Store, next: &[TransactionBranch]) -> Result<(), errors::Error> {
+        event!(Level::DEBUG, gid= ?self.gid, action= "branch advance", branch_id= ?self.branch_id, next= next.len());
+        self.attempts += 1;
+        self.finished_at = Some(Local::now());
+        self.state = State::Succeed;
+        db.advance_branch(self, next).await
+    }
+
+    pub async fn update_state(&mut self, db: &dyn Store, state: State) -> Result<(), errors::Error> {
         event!(Level::DEBUG, gid= ?self.gid, action= "branch change state", state= ?state, branch_id= ?self.branch_id);
-        let now = Local::now();
-        let finished_at: DateTime<Utc> = now.with_timezone(&Utc);
-        db.update(
-            Update::table(TransactionBranch::tablename())
-                .set("state", state)
-                .set("finished_at", finished_at)
-                .so_that(
-                    "gid"
-                        .equals(self.gid)
-                        .and("branch_id".equals(self.branch_id)),
-                ),
-        )
-        .await?;
-        self.finished_at = Some(now);
+        self.finished_at = Some(Local::now());
         self.state = state;
-        Ok(())
+        db.update_branch(self).await?;
+        db.insert_event(&Event::transition(self.gid, state, None).with_branch(self))
+            .await
     }
 }
 
 
-#[derive(Debug, Serialize)]
-pub struct BranchParam<'a> {
+/// Query params attached to every request luwu sends to RM.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BranchParam {
     gid: Gid,
     branch_id: Gid,
-    r#type: &'a ProcessorType,
+    r#type: String,
     branch_type: String,
 }
 
+impl BranchParam {
+    pub fn new(gid: Gid, branch_id: Gid, r#type: String, branch_type: String) -> BranchParam {
+        BranchParam {
+            gid,
+            branch_id,
+            r#type,
+            branch_type,
+        }
+    }
+
+    pub fn gid(&self) -> Gid {
+        self.gid
+    }
+
+    pub fn branch_id(&self) -> Gid {
+        self.branch_id
+    }
+
+    pub fn r#type(&self) -> &str {
+        &self.r#type
+    }
+
+    pub fn branch_type(&self) -> &str {
+        &self.branch_type
+    }
+}
+
 impl Transaction {
     // Process process global transaction once
-    pub async fn process(&mut self, db: &Conn) -> Result<(), errors::Error> {
+    pub async fn process(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
         debug!("processing: {} state: {:?}", self.gid, self.state);
         let _defer = Defer::new(Box::new({
             let gid = self.gid.clone();
@@ -309,115 +709,236 @@ impl Transaction {
         }));
         match (self.state, self.r#type()) {
             (State::Prepared, &ProcessorType::Message(_)) => {
+                // 事务消息需要先回查 query_prepared
             }
-            _ => {
-                self.update_state(db, State::Aborting).await?;
-            }
+            (State::Prepared, _) => match self.deadline() {
+                // 到期之前保持 prepared，等待 AP 提交
+                Some(deadline) if deadline > Local::now() => return Ok(()),
+                _ => self.abort(db, "timeout").await?,
+            },
+            _ => {}
         };
-        let branches: Vec<TransactionBranch> = quaint::serde::from_rows(
-            db.select(
-                Select::from_table(TransactionBranch::tablename())
-                    .so_that("gid".equals(self.gid))
-                    .order_by("id".ascend()),
-            )
-            .await?,
-        )?;
+        let mut branches = self.branches(db).await?;
         let mut processor = self.processor();
-        processor.once(db, &branches).await?;
+        processor.once(db, &mut branches).await?;
         Ok(())
     }
 
-    pub fn branch_params<'a>(&'a self, branch: &TransactionBranch) -> BranchParam<'a> {
+    pub fn branch_params(&self, branch: &TransactionBranch) -> BranchParam {
         BranchParam {
             gid: self.gid,
-            r#type: self.r#type(),
+            r#type: self.r#type().tag().to_string(),
             branch_id: branch.branch_id(),
             branch_type: branch.r#type().to_string(),
         }
     }
 
-    pub async fn save(&mut self, db: &Conn) -> Result<(), errors::Error> {
-        let config = CONFIG.get().unwrap();
-        let db = db.start_transaction().await?;
-        self.set_scheduled_at(config.delay);
+    pub async fn save(&mut self, db: &dyn Store) -> Result<(), errors::Error> {
+        self.set_scheduled_at(self.retry_policy().initial_interval());
         event!(Level::DEBUG, gid = ?self.gid, action = "create transaction", state = ?self.state, branch = "", payload = ?self.payload);
-        // r#type: String,
-        // data: String,
-        // state: State,
-        // query_prepared: String,
-        // committed_at: Option<DateTime<Local>>,
-        // finished_at: Option<DateTime<Local>>,
-        // rollbacked_at: Option<DateTime<Local>>,
-        // next_cron_interval: i64,
-        // scheduled_at: Option<DateTime<Local>>,
-        // created_at: DateTime<Local>,
-        // last_modified: DateTime<Local>,
-        let insertion = Insert::single_into(Transaction::tablename())
-            .value("gid", self.gid)
-            .value("payload", self.payload.as_str())
-            .build()
-            .on_conflict(OnConflict::DoNothing);
-        let set = db.insert(insertion).await?;
-        if !set.is_empty() {
-            // 如果这个是新事务，保存所有的分支
-            let branches = self.processor().branches();
-            if !branches.is_empty() {
-                event!(Level::DEBUG, gid = ?self.gid, action = "save branches", state = ?self.state, payload = ?branches);
-                // gid: Gid,
-                // url: String,
-                // data: String,
-                // branch_id: Gid,
-                // r#type: String,
-                // state: State,
-                // finished_at: DateTime<Local>,
-                // rollbacked_at: DateTime<Local>,
-                // created_at: DateTime<Local>,
-                // last_modified: DateTime<Local>,
-                let mut insertion = Insert::multi_into(
-                    TransactionBranch::tablename(),
-                    vec!["gid", "data", "branch_id", "type", "state"],
-                );
-                for branch in branches.iter() {
-                    insertion = insertion.values((
-                        branch.gid,
-                        branch.payload.clone(),
-                        branch.branch_id,
-                        branch.r#type.clone(),
-                        branch.state,
-                    ))
-                }
-                let insertion = insertion.build().on_conflict(OnConflict::DoNothing);
-                db.insert(insertion).await?;
-            }
+        let branches = self.processor().branches();
+        if db.create(self, &branches).await? {
+            event!(Level::DEBUG, gid = ?self.gid, action = "save branches", state = ?self.state, payload = ?branches);
+            metrics::transaction_created(self.r#type.tag());
         } else if self.state == State::Submitted {
             // 如果数据库已经存放了prepared的事务，则修改状态
-            let scheduled_at: DateTime<Utc> =
-                self.scheduled_at.as_ref().unwrap().with_timezone(&Utc);
-            let up = Update::table(Transaction::tablename())
-                .set("scheduled_at", scheduled_at)
-                .set("delay", self.delay)
-                .set("state", self.state)
-                .so_that("gid".equals(self.gid));
-            db.update(up).await?;
+            if !db.update_state(self, State::Prepared).await? {
+                // 重复提交不需要修改，其他状态说明已经被回滚或处理完
+                let current = Transaction::load(self.gid, db).await?.state();
+                if current != State::Submitted {
+                    return Err(errors::Error::CannotSubmitTransaction(self.gid, current.tag().to_string()));
+                }
+                return Ok(());
+            }
+        } else {
+            return Ok(());
         }
-        db.commit().await?;
-        Ok(())
-        // e2p(err)
+        db.insert_event(&Event::transition(self.gid, self.state, None)).await
+    }
+
+    /// Pick up one expired transaction which is still in progress and not leased
+    /// by another instance, and take its lease. Other instances can pick it up
+    /// again only after the lease expired, call `reschedule` after processing.
+    pub async fn lock_expired(db: &dyn Store) -> Result<Option<Transaction>, errors::Error> {
+        let lease = Gid::new_v4();
+        let mut tx = match db.claim_due(lease, Self::lease_expires_at()).await? {
+            Some(tx) => tx,
+            None => return Ok(None),
+        };
+        tx.lease = Some(lease);
+        // 认领不是一次调用结果的重试，只记日志，不写入事件
+        debug!("picked up transaction {} with lease {}", tx.gid, lease);
+        Ok(Some(tx))
+    }
+
+    /// Keep an unfinished transaction from being due right after a pass, the
+    /// interval grows only if the pass `failed` and did not reschedule it.
+    pub async fn reschedule(&mut self, db: &dyn Store, failed: bool) -> Result<(), errors::Error> {
+        let finished = matches!(self.state, State::Succeed | State::Failed);
+        let due = self.scheduled_at.map(|at| at <= Local::now()).unwrap_or(true);
+        if finished || !due {
+            return Ok(());
+        }
+        let policy = self.retry_policy();
+        let delay = self.delay.max(policy.initial_interval());
+        let delay = if failed { policy.next(delay) } else { delay };
+        self.touch(db, delay).await
+    }
+
+    /// Register branches of tcc or xa, only allowed while the transaction is prepared.
+    pub async fn register_branches(
+        &mut self,
+        db: &dyn Store,
+        branches: &[TransactionBranch],
+    ) -> Result<(), errors::Error> {
+        event!(Level::DEBUG, gid = ?self.gid, action = "register branches", state = ?self.state, payload = ?branches);
+        db.register_branches(self.gid, branches).await
     }
 
     // TransFromDb construct trans from db
-    pub async fn load(gid: Gid, db: &Conn) -> Result<Transaction, errors::Error> {
-        let q = Select::from_table(Transaction::tablename())
-            .so_that("gid".equals(gid))
-            .limit(1);
-        Ok(db.select(q).await?.from_first()?)
-        /*
-            if dbr.Error == gorm.ErrRecordNotFound {
-                return nil
-            }
-            e2p(dbr.Error)
-            return &m
-        */
+    pub async fn load(gid: Gid, db: &dyn Store) -> Result<Transaction, errors::Error> {
+        db.load(gid)
+            .await?
+            .ok_or(errors::Error::TransactionNotFound(gid))
+    }
+}
+
+impl Table for Transaction {
+    fn tablename() -> &'static str {
+        "tx_transactions"
+    }
+
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "gid",
+            "state",
+            "type",
+            "processor",
+            "payload",
+            "query_prepared",
+            "retry_policy",
+            "timeout_to_fail",
+            "options",
+            "reason",
+            "committed_at",
+            "finished_at",
+            "rollbacked_at",
+            "delay",
+            "scheduled_at",
+            "owner",
+            "lease_expires_at",
+            "created_at",
+            "last_modified",
+        ]
+    }
+
+    fn values(&self) -> Vec<Value<'static>> {
+        vec![
+            Value::from(self.gid),
+            Value::from(self.state),
+            json_value(Some(&self.r#type)),
+            // 冗余的处理器类型，用于按类型查询
+            Value::from(self.r#type.tag().to_string()),
+            Value::from(self.payload.clone()),
+            Value::from(self.query_prepared.clone()),
+            json_value(self.retry_policy.as_ref()),
+            Value::Integer(self.timeout_to_fail),
+            json_value(self.options.as_ref()),
+            Value::Text(self.reason.clone().map(Into::into)),
+            datetime_value(self.committed_at.as_ref()),
+            datetime_value(self.finished_at.as_ref()),
+            datetime_value(self.rollbacked_at.as_ref()),
+            Value::from(self.delay),
+            datetime_value(self.scheduled_at.as_ref()),
+            Value::Uuid(self.owner),
+            datetime_value(self.lease_expires_at.as_ref()),
+            datetime_value(Some(&self.created_at)),
+            datetime_value(Some(&self.last_modified)),
+        ]
+    }
+
+    fn from_row(row: &ResultRow) -> Result<Transaction, errors::Error> {
+        Ok(Transaction {
+            gid: mapping::uuid(row, "gid")?,
+            state: mapping::state(row, "state")?,
+            r#type: mapping::json(row, "type")?,
+            payload: mapping::text(row, "payload")?,
+            query_prepared: mapping::text(row, "query_prepared")?,
+            retry_policy: mapping::opt_json(row, "retry_policy")?,
+            timeout_to_fail: mapping::opt_integer(row, "timeout_to_fail")?,
+            options: mapping::opt_json(row, "options")?,
+            reason: mapping::opt_text(row, "reason")?,
+            committed_at: mapping::opt_datetime(row, "committed_at")?,
+            finished_at: mapping::opt_datetime(row, "finished_at")?,
+            rollbacked_at: mapping::opt_datetime(row, "rollbacked_at")?,
+            delay: mapping::integer(row, "delay")?,
+            scheduled_at: mapping::opt_datetime(row, "scheduled_at")?,
+            owner: mapping::opt_uuid(row, "owner")?,
+            lease_expires_at: mapping::opt_datetime(row, "lease_expires_at")?,
+            lease: None,
+            created_at: mapping::datetime(row, "created_at")?,
+            last_modified: mapping::datetime(row, "last_modified")?,
+        })
+    }
+}
+
+impl Table for TransactionBranch {
+    fn tablename() -> &'static str {
+        "tx_transaction_branches"
+    }
+
+    fn columns() -> Vec<&'static str> {
+        vec![
+            "gid",
+            "branch_id",
+            "type",
+            "state",
+            "url",
+            "payload",
+            "retry_policy",
+            "attempts",
+            "options",
+            "finished_at",
+            "rollbacked_at",
+            "created_at",
+            "last_modified",
+        ]
+    }
+
+    fn values(&self) -> Vec<Value<'static>> {
+        vec![
+            Value::from(self.gid),
+            Value::from(self.branch_id),
+            Value::from(self.r#type.clone()),
+            Value::from(self.state),
+            Value::from(self.url.clone()),
+            Value::from(self.payload.clone()),
+            json_value(self.retry_policy.as_ref()),
+            Value::from(self.attempts),
+            json_value(self.options.as_ref()),
+            datetime_value(self.finished_at.as_ref()),
+            datetime_value(self.rollbacked_at.as_ref()),
+            datetime_value(Some(&self.created_at)),
+            datetime_value(Some(&self.last_modified)),
+        ]
+    }
+
+    fn from_row(row: &ResultRow) -> Result<TransactionBranch, errors::Error> {
+        Ok(TransactionBranch {
+            gid: mapping::uuid(row, "gid")?,
+            branch_id: mapping::uuid(row, "branch_id")?,
+            r#type: mapping::text(row, "type")?,
+            state: mapping::state(row, "state")?,
+            url: mapping::text(row, "url")?,
+            payload: mapping::text(row, "payload")?,
+            retry_policy: mapping::opt_json(row, "retry_policy")?,
+            attempts: mapping::integer(row, "attempts")?,
+            options: mapping::opt_json(row, "options")?,
+            finished_at: mapping::opt_datetime(row, "finished_at")?,
+            rollbacked_at: mapping::opt_datetime(row, "rollbacked_at")?,
+            created_at: mapping::datetime(row, "created_at")?,
+            last_modified: mapping::datetime(row, "last_modified")?,
+        })
     }
 }
 
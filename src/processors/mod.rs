@@ -1,27 +1,238 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
+use std::time::Instant;
 
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::models::transaction::{Transaction, TransactionBranch};
-use crate::database::Conn;
+use crate::models::transaction::{Gid, State, Transaction, TransactionBranch};
+use crate::models::{Event, RequestOptions, RetryPolicy};
+use crate::store::Store;
+use crate::config::CONFIG;
 use crate::errors;
+use crate::http;
+use crate::metrics;
 
 mod tx_tcc_processor;
 mod tx_saga_processor;
 mod tx_xa_processor;
 mod tx_message_processor;
+mod tx_state_machine_processor;
 
 pub use tx_xa_processor::TxXaProcessor;
 pub use tx_tcc_processor::TxTCCProcessor;
 pub use tx_saga_processor::TxSagaProcessor;
 pub use tx_message_processor::TxMessageProcessor;
+pub use tx_state_machine_processor::TxStateMachineProcessor;
 
 #[async_trait]
 pub trait Processor<'tx>: Debug + Send {
     fn with_transaction(tx: &'tx mut Transaction) -> Box<dyn Processor<'tx> + Send + 'tx> where Self: Sized;
     fn branches(&self) -> Vec<TransactionBranch>;
-    async fn once(&mut self, db: &Conn, branches: &[TransactionBranch]) -> Result<(), errors::Error>;
-    async fn exec(&mut self, db: &Conn, branch: &TransactionBranch) -> Result<(), errors::Error>;
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error>;
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error>;
+}
+
+/// RM 的返回结果，参见 docs/src/protocal.md
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// { "message": "Ok" }
+    Succeed,
+    /// { "message": "...", "code": 5020 }，业务需要回滚
+    Failed,
+    /// 其他结果都不确定，需要重试
+    Retry,
+}
+
+#[derive(Debug, Deserialize)]
+struct RMResponse {
+    message: String,
+    #[serde(default)]
+    code: Option<u16>,
+}
+
+impl Outcome {
+    pub fn from_body(body: &str) -> Outcome {
+        match serde_json::from_str::<RMResponse>(body) {
+            Ok(RMResponse { code: Some(5020), .. }) => Outcome::Failed,
+            Ok(RMResponse { message, code: None }) if message == "Ok" => Outcome::Succeed,
+            Ok(RMResponse { message, .. }) if message == "FAILURE" => Outcome::Failed,
+            // 兼容直接返回 SUCCESS/FAILURE 文本的 RM
+            Err(_) if body.trim() == "SUCCESS" => Outcome::Succeed,
+            Err(_) if body.trim() == "FAILURE" => Outcome::Failed,
+            _ => Outcome::Retry,
+        }
+    }
+
+    /// Like `from_body`, but a success is uncertain unless the status is 2xx,
+    /// an explicit failure counts whatever the status is.
+    pub fn from_response(status: u16, body: &str) -> Outcome {
+        match Outcome::from_body(body) {
+            Outcome::Succeed if !(200..300).contains(&status) => Outcome::Retry,
+            outcome => outcome,
+        }
+    }
+}
+
+/// A request to the branch with the shared client, carrying the options of the
+/// transaction and the branch.
+pub(crate) fn post(tx: &Transaction, branch: &TransactionBranch) -> reqwest::RequestBuilder {
+    let req = http::client()
+        .post(branch.url())
+        .query(&tx.branch_params(branch))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(reqwest::header::ACCEPT, "application/json");
+    // 分支的选项覆盖事务的选项
+    let options: Vec<&RequestOptions> = tx.options().into_iter().chain(branch.options()).collect();
+    with_options(req, &options)
+}
+
+/// Apply timeout, credential and headers of `options`, later ones take precedence.
+pub(crate) fn with_options(mut req: reqwest::RequestBuilder, options: &[&RequestOptions]) -> reqwest::RequestBuilder {
+    if let Some(timeout) = options.iter().rev().find_map(|options| options.timeout()) {
+        req = req.timeout(std::time::Duration::from_secs(timeout.max(1) as u64));
+    }
+    let mut headers = BTreeMap::new();
+    if let Some(name) = options.iter().rev().find_map(|options| options.credential()) {
+        match CONFIG.get().and_then(|config| config.credential(name)) {
+            Some(credential) => {
+                if let Some(token) = credential.token() {
+                    headers.insert("authorization".to_string(), format!("Bearer {}", token));
+                }
+                for (name, value) in credential.headers() {
+                    headers.insert(name.to_ascii_lowercase(), value.clone());
+                }
+            }
+            None => warn!("credential {} is not configured", name),
+        }
+    }
+    for options in options.iter() {
+        for (name, value) in options.headers() {
+            headers.insert(name.to_ascii_lowercase(), value.clone());
+        }
+    }
+    for (name, value) in headers.iter() {
+        req = req.header(name.as_str(), value.as_str());
+    }
+    req
+}
+
+/// Read the body of RM's response, a request error is an uncertain result.
+pub(crate) async fn read_response(resp: reqwest::Result<reqwest::Response>) -> (Outcome, String) {
+    let resp = match resp {
+        Ok(resp) => resp,
+        Err(err) => return (Outcome::Retry, err.to_string()),
+    };
+    let status = resp.status().as_u16();
+    match resp.text().await {
+        Ok(body) => (Outcome::from_response(status, &body), body),
+        Err(err) => (Outcome::Retry, err.to_string()),
+    }
+}
+
+/// RM 的返回，以及调用的耗时
+#[derive(Debug)]
+pub(crate) struct Reply {
+    url: String,
+    status: Option<u16>,
+    latency: i64,
+    outcome: Outcome,
+    body: String,
+}
+
+impl Reply {
+    pub(crate) fn outcome(&self) -> Outcome {
+        self.outcome
+    }
+
+    pub(crate) fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// The call to be recorded in the events of the transaction.
+    pub(crate) fn event(&self, gid: Gid) -> Event {
+        Event::call(gid, &self.url, self.status, self.latency, &self.body)
+    }
+}
+
+/// Send the request and read the response, timing the whole call.
+pub(crate) async fn send(req: reqwest::RequestBuilder) -> Reply {
+    let started = Instant::now();
+    let url = req
+        .try_clone()
+        .and_then(|req| req.build().ok())
+        .map(|req| req.url().clone());
+    let resp = req.send().await;
+    let status = resp.as_ref().ok().map(|resp| resp.status().as_u16());
+    let (outcome, body) = read_response(resp).await;
+    let latency = started.elapsed();
+    let host = url
+        .as_ref()
+        .and_then(|url| url.host_str().map(|host| format!("{}:{}", host, url.port_or_known_default().unwrap_or(0))))
+        .unwrap_or_default();
+    metrics::branch_called(&host, latency);
+    Reply {
+        url: url.map(|url| url.to_string()).unwrap_or_default(),
+        status,
+        latency: latency.as_millis() as i64,
+        outcome,
+        body,
+    }
+}
+
+/// Record the call and apply the reply of RM to the branch.
+///
+/// Only branches which are `failable`, for example saga's `on_committing` and tcc's `try`,
+/// can be marked as failed, otherwise a failure is treated as retryable.
+pub(crate) async fn handle_response(
+    tx: &mut Transaction,
+    db: &dyn Store,
+    branch: &mut TransactionBranch,
+    reply: Reply,
+    failable: bool,
+) -> Result<(), errors::Error> {
+    db.insert_event(&reply.event(tx.gid()).with_branch(branch)).await?;
+    // 状态机的动作成功时，结果和尝试次数已经由 advance 写入，不再重复写分支
+    let applied = branch.state() == State::Succeed;
+    if !applied {
+        branch.attempt(db).await?;
+    }
+    let initial_interval = tx.retry_policy().initial_interval();
+    let policy = match branch.retry_policy() {
+        Some(policy) => policy.clone(),
+        None => tx.retry_policy(),
+    };
+    match reply.outcome {
+        Outcome::Succeed => {
+            if applied {
+                db.insert_event(&Event::transition(tx.gid(), State::Succeed, None).with_branch(branch))
+                    .await?;
+            } else {
+                branch.update_state(db, State::Succeed).await?;
+            }
+            tx.touch(db, initial_interval).await?;
+        }
+        Outcome::Failed if failable => {
+            branch.update_state(db, State::Failed).await?;
+            tx.touch(db, initial_interval).await?;
+        }
+        // 只有正向操作可以放弃重试，按失败处理并回滚
+        Outcome::Retry if failable && policy.exhausted(branch.attempts()) => {
+            warn!("branch {} gave up after {} attempts: {}", branch.url(), branch.attempts(), reply.body);
+            branch.update_state(db, State::Failed).await?;
+            tx.touch(db, initial_interval).await?;
+        }
+        _ => {
+            let delay = policy.backoff(branch.attempts());
+            tx.touch(db, delay).await?;
+            let detail = format!("{} attempts of {}", branch.attempts(), branch.url());
+            db.insert_event(&Event::retry(tx.gid(), delay, detail).with_branch(branch))
+                .await?;
+            metrics::retried(tx.r#type().tag());
+            return Err(errors::Error::UnexpectedResponse(branch.url().to_string(), reply.body));
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,13 +248,64 @@ pub struct TCC {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Saga {
     steps: Vec<SagaStep>,
+    /// 并发执行没有依赖关系的步骤，否则按顺序逐个执行
+    #[serde(default)]
+    concurrent: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl Saga {
+    pub fn new(steps: Vec<SagaStep>, concurrent: bool) -> Saga {
+        Saga { steps, concurrent }
+    }
+
+    pub fn steps(&self) -> &[SagaStep] {
+        &self.steps
+    }
+
+    pub fn concurrent(&self) -> bool {
+        self.concurrent
+    }
+
+    /// Indexes of the steps which must succeed before the step at `idx` runs.
+    pub fn dependencies(&self, idx: usize) -> Vec<usize> {
+        match (self.concurrent, self.steps.get(idx)) {
+            (true, Some(step)) => step.depends_on.clone(),
+            (false, Some(_)) if idx > 0 => vec![idx - 1],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        for (idx, step) in self.steps.iter().enumerate() {
+            // 只允许依赖前面的步骤，这样就不会有环
+            if let Some(dep) = step.depends_on.iter().find(|&&dep| dep >= idx) {
+                return Err(errors::Error::InvalidDefinition(format!(
+                    "saga step {} can only depend on previous steps, but {} found.",
+                    idx, dep
+                )));
+            }
+            if let Some(policy) = step.retry_policy.as_ref() {
+                policy.validate()?;
+            }
+            if let Some(options) = step.options.as_ref() {
+                options.validate()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SagaStep {
     payload: String,
     on_reverting: String,
     on_committing: String,
+    #[serde(default)]
+    depends_on: Vec<usize>,
+    #[serde(default)]
+    retry_policy: Option<RetryPolicy>,
+    #[serde(default)]
+    options: Option<RequestOptions>,
 }
 
 impl SagaStep {
@@ -51,10 +313,40 @@ impl SagaStep {
         SagaStep {
             payload,
             on_reverting,
-            on_committing
+            on_committing,
+            depends_on: Vec::new(),
+            retry_policy: None,
+            options: None,
         }
     }
 
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> SagaStep {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub fn with_options(mut self, options: RequestOptions) -> SagaStep {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn options(&self) -> Option<&RequestOptions> {
+        self.options.as_ref()
+    }
+
+    pub fn with_depends_on(mut self, depends_on: Vec<usize>) -> SagaStep {
+        self.depends_on = depends_on;
+        self
+    }
+
+    pub fn depends_on(&self) -> &[usize] {
+        &self.depends_on
+    }
+
     pub fn on_reverting(&self) -> &str {
         &self.on_reverting
     }
@@ -62,6 +354,10 @@ impl SagaStep {
     pub fn on_committing(&self) -> &str {
         &self.on_committing
     }
+
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -71,22 +367,57 @@ pub struct Message {
 }
 
 impl Message {
+    pub fn new(steps: Vec<MessageStep>, query_prepared: String) -> Message {
+        Message {
+            steps,
+            query_prepared,
+        }
+    }
+
+    pub fn steps(&self) -> &[MessageStep] {
+        &self.steps
+    }
+
+    pub fn query_prepared(&self) -> &str {
+        &self.query_prepared
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        for step in self.steps.iter() {
+            if let Some(options) = step.options.as_ref() {
+                options.validate()?;
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageStep {
     payload: String,
     callback: String,
+    #[serde(default)]
+    options: Option<RequestOptions>,
 }
 
 impl MessageStep {
     pub fn new(payload: String, callback: String) -> MessageStep {
         MessageStep {
             payload,
-            callback
+            callback,
+            options: None,
         }
     }
 
+    pub fn with_options(mut self, options: RequestOptions) -> MessageStep {
+        self.options = Some(options);
+        self
+    }
+
+    pub fn options(&self) -> Option<&RequestOptions> {
+        self.options.as_ref()
+    }
+
     pub fn payload(&self) -> &str {
         &self.payload
     }
@@ -96,6 +427,188 @@ impl MessageStep {
     }
 }
 
+/// SAGA 状态机模式，每个状态执行一个动作，根据 RM 返回的字段选择下一个状态
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateMachine {
+    initial: String,
+    states: HashMap<String, MachineState>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MachineState {
+    #[serde(default)]
+    action: Option<String>,
+    #[serde(default)]
+    compensation: Option<String>,
+    #[serde(default)]
+    payload: String,
+    /// 按顺序匹配，都不满足时使用 `next`
+    #[serde(default)]
+    transitions: Vec<Transition>,
+    #[serde(default)]
+    next: Option<String>,
+    /// 终止状态
+    #[serde(default)]
+    end: Option<End>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Transition {
+    /// JSON pointer into the response of the action, for example `/data/status`.
+    field: String,
+    equals: serde_json::Value,
+    next: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum End {
+    Succeed,
+    Failed,
+}
+
+impl StateMachine {
+    pub fn initial(&self) -> &str {
+        &self.initial
+    }
+
+    pub fn state(&self, name: &str) -> Option<&MachineState> {
+        self.states.get(name)
+    }
+
+    /// Branch id of a state, stable for the same transaction.
+    pub fn branch_id(gid: Gid, name: &str) -> Gid {
+        Gid::new_v5(&gid, name.as_bytes())
+    }
+
+    /// Find out which state the branch belongs to.
+    pub fn state_of(&self, gid: Gid, branch_id: Gid) -> Option<&str> {
+        self.states
+            .keys()
+            .find(|name| Self::branch_id(gid, name) == branch_id)
+            .map(|name| name.as_str())
+    }
+
+    /// Branches to save when entering the state.
+    pub fn branches(&self, gid: Gid, name: &str) -> Vec<TransactionBranch> {
+        let state = match self.states.get(name) {
+            Some(state) => state,
+            None => return Vec::new(),
+        };
+        let branch_id = Self::branch_id(gid, name);
+        let mut branches = Vec::with_capacity(2);
+        if state.end.is_some() {
+            branches.push(TransactionBranch::new(
+                gid,
+                branch_id,
+                "end".to_string(),
+                State::Succeed,
+                String::new(),
+                state.payload.clone(),
+            ));
+            return branches;
+        }
+        if let Some(compensation) = state.compensation.as_ref() {
+            branches.push(TransactionBranch::new(
+                gid,
+                branch_id,
+                "compensation".to_string(),
+                State::Prepared,
+                compensation.clone(),
+                state.payload.clone(),
+            ));
+        }
+        if let Some(action) = state.action.as_ref() {
+            branches.push(TransactionBranch::new(
+                gid,
+                branch_id,
+                "action".to_string(),
+                State::Prepared,
+                action.clone(),
+                state.payload.clone(),
+            ));
+        }
+        branches
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        let invalid = |msg: String| Err(errors::Error::InvalidDefinition(msg));
+        match self.states.get(&self.initial) {
+            Some(state) if state.end.is_none() => {}
+            _ => return invalid(format!("initial state `{}` must be a non-terminal state.", self.initial)),
+        }
+        for (name, state) in self.states.iter() {
+            if state.end.is_some() {
+                if state.action.is_some() || state.next.is_some() || !state.transitions.is_empty() {
+                    return invalid(format!("terminal state `{}` can not have action or transitions.", name));
+                }
+                continue;
+            }
+            if state.action.is_none() || state.next.is_none() {
+                return invalid(format!("state `{}` must have an action and a default `next`.", name));
+            }
+            for next in state.successors() {
+                if !self.states.contains_key(next) {
+                    return invalid(format!("state `{}` transits to unknown state `{}`.", name, next));
+                }
+            }
+        }
+        // 每个状态最多执行一次，所以不允许有环
+        let mut done = HashSet::new();
+        for name in self.states.keys() {
+            self.check_acyclic(name, &mut Vec::new(), &mut done)?;
+        }
+        Ok(())
+    }
+
+    fn check_acyclic<'a>(
+        &'a self,
+        name: &'a str,
+        visiting: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Result<(), errors::Error> {
+        if done.contains(name) {
+            return Ok(());
+        }
+        if visiting.contains(&name) {
+            return Err(errors::Error::InvalidDefinition(format!(
+                "state `{}` is reachable from itself.",
+                name
+            )));
+        }
+        visiting.push(name);
+        for next in self.states[name].successors() {
+            self.check_acyclic(next, visiting, done)?;
+        }
+        visiting.pop();
+        done.insert(name);
+        Ok(())
+    }
+}
+
+impl MachineState {
+    pub fn end(&self) -> Option<End> {
+        self.end
+    }
+
+    fn successors(&self) -> impl Iterator<Item = &str> {
+        self.transitions
+            .iter()
+            .map(|transition| transition.next.as_str())
+            .chain(self.next.as_deref())
+    }
+
+    /// Choose the next state by the response of the action.
+    pub fn next(&self, body: &str) -> Option<&str> {
+        let resp: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        self.transitions
+            .iter()
+            .find(|transition| resp.pointer(&transition.field) == Some(&transition.equals))
+            .map(|transition| transition.next.as_str())
+            .or(self.next.as_deref())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "lowercase")]
@@ -104,6 +617,8 @@ pub enum ProcessorType {
     TCC(TCC),
     Saga(Saga),
     Message(Message),
+    #[serde(rename = "state_machine")]
+    StateMachine(StateMachine),
 }
 
 impl ProcessorType {
@@ -112,7 +627,17 @@ impl ProcessorType {
             ProcessorType::Xa(_) => Box::new(|tx| TxXaProcessor::with_transaction(tx)),
             ProcessorType::TCC(_) => Box::new(|tx| TxTCCProcessor::with_transaction(tx)),
             ProcessorType::Saga(_) => Box::new(|tx| TxSagaProcessor::with_transaction(tx)),
-            ProcessorType::Message(_) => Box::new(|tx| TxMessageProcessor::with_transaction(tx))
+            ProcessorType::Message(_) => Box::new(|tx| TxMessageProcessor::with_transaction(tx)),
+            ProcessorType::StateMachine(_) => Box::new(|tx| TxStateMachineProcessor::with_transaction(tx)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), errors::Error> {
+        match self {
+            ProcessorType::Saga(saga) => saga.validate(),
+            ProcessorType::Message(message) => message.validate(),
+            ProcessorType::StateMachine(machine) => machine.validate(),
+            _ => Ok(()),
         }
     }
 
@@ -121,7 +646,8 @@ impl ProcessorType {
             ProcessorType::Xa(_) => "xa",
             ProcessorType::TCC(_) => "tcc",
             ProcessorType::Saga(_) => "saga",
-            ProcessorType::Message(_) => "message"
+            ProcessorType::Message(_) => "message",
+            ProcessorType::StateMachine(_) => "state_machine",
         }
     }
 }
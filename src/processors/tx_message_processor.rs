@@ -1,16 +1,16 @@
-use std::collections::HashMap;
-
-use quaint::pooled::PooledConnection;
+use chrono::{Duration, Local};
 use rocket::serde::uuid::Uuid;
 use serde::Serialize;
+use tracing::warn;
 
 use crate::config::CONFIG;
 use crate::errors;
+use crate::http;
 use crate::models::transaction::{State, Transaction, TransactionBranch};
+use crate::models::RequestOptions;
+use crate::store::Store;
 
-use super::{Message, MessageStep, Processor};
-
-type Conn = PooledConnection;
+use super::{handle_response, post, send, with_options, Outcome, Processor, ProcessorType};
 
 #[derive(Debug)]
 pub struct TxMessageProcessor<'tx> {
@@ -25,8 +25,11 @@ impl<'tx> Processor<'tx> for TxMessageProcessor<'tx> {
     }
 
     fn branches(&self) -> Vec<TransactionBranch> {
-        let steps: Vec<MessageStep> = serde_json::from_str(self.tx.payload()).unwrap();
-        let mut branches = Vec::with_capacity(3);
+        let steps = match self.tx.r#type() {
+            ProcessorType::Message(message) => message.steps(),
+            _ => unreachable!(),
+        };
+        let mut branches = Vec::with_capacity(steps.len());
         for step in steps {
             branches.push(TransactionBranch::new(
                 self.tx.gid(),
@@ -35,46 +38,18 @@ impl<'tx> Processor<'tx> for TxMessageProcessor<'tx> {
                 State::Prepared,
                 step.callback.to_string(),
                 step.payload.to_string(),
-            ));
+            ).with_options(step.options.clone()));
         }
         branches
     }
 
-    async fn exec(&mut self, db: &Conn, branch: &TransactionBranch) -> Result<(), errors::Error> {
-        let cli = reqwest::Client::new();
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            reqwest::header::CONTENT_TYPE,
-            "application/json".try_into().unwrap(),
-        );
-        headers.insert(
-            reqwest::header::ACCEPT,
-            "application/json".try_into().unwrap(),
-        );
-        let resp = cli
-            .post(branch.url())
-            .body(branch.payload().to_string())
-            .headers(headers)
-            .send()
-            .await?
-            .json::<HashMap<String, serde_json::Value>>()
-            .await?;
-        dbg!(&resp);
-        /*
-        body := resp.String()
-        if strings.Contains(body, "SUCCESS") {
-            branch.changeStatus(db, "succeed")
-            t.touch(db, config.TransCronInterval)
-        } else {
-            panic(fmt.Errorf("unknown response: %s, will be retried", body))
-        }
-        */
-        let config = CONFIG.get().unwrap();
-        self.tx.touch(db, config.delay).await?;
-        Ok(())
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error> {
+        let reply = send(post(self.tx, branch).body(branch.payload().to_string())).await;
+        // 消息已经提交，下游只能重试直到成功
+        handle_response(self.tx, db, branch, reply, false).await
     }
 
-    async fn once(&mut self, db: &Conn, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error> {
         self.maybe_query_prepared(db).await?;
         match self.tx.state() {
             State::Submitted => {
@@ -84,7 +59,7 @@ impl<'tx> Processor<'tx> for TxMessageProcessor<'tx> {
                 return Ok(());
             }
         }
-        for branch in branches {
+        for branch in branches.iter_mut() {
             match (branch.r#type(), branch.state()) {
                 ("action", State::Prepared) => {
                     //
@@ -109,7 +84,8 @@ impl<'tx> Processor<'tx> for TxMessageProcessor<'tx> {
 }
 
 impl<'a> TxMessageProcessor<'a> {
-    async fn maybe_query_prepared(&mut self, db: &Conn) -> Result<bool, errors::Error> {
+    /// Check a prepared message by `query_prepared`, return true if its state changed.
+    async fn maybe_query_prepared(&mut self, db: &dyn Store) -> Result<bool, errors::Error> {
         match self.tx.state() {
             State::Prepared => {
                 // Only accept this.
@@ -118,26 +94,41 @@ impl<'a> TxMessageProcessor<'a> {
                 return Ok(false);
             }
         }
-        let cli = reqwest::Client::new();
         #[derive(Debug, Serialize)]
         struct Q {
             gid: Uuid,
         }
-        let resp = cli
+        let req = http::client()
             .get(self.tx.query_prepared())
-            .query(&Q { gid: self.tx.gid() })
-            .send()
-            .await?;
-        dbg!(&resp);
-        // resp, err := common.RestyClient.R().SetQueryParam("gid", t.Gid).Get(t.QueryPrepared)
-        // body := resp.String()
-        // if strings.Contains(body, "SUCCESS") {
-        // 	t.changeStatus(db, "submitted")
-        // } else {
-        // 	t.touch(db, t.NextCronInterval*2)
-        // }
-        let config = CONFIG.get().unwrap();
-        self.tx.touch(db, config.delay).await?;
+            .query(&Q { gid: self.tx.gid() });
+        // 与调用分支一样带上事务的超时、凭证和请求头
+        let options: Vec<&RequestOptions> = self.tx.options().into_iter().collect();
+        let reply = send(with_options(req, &options)).await;
+        db.insert_event(&reply.event(self.tx.gid())).await?;
+        if reply.outcome() == Outcome::Retry {
+            warn!("query prepared message {} is uncertain: {}", self.tx.gid(), reply.body());
+        }
+        match reply.outcome() {
+            Outcome::Succeed => {
+                self.tx.update_state(db, State::Submitted).await?;
+            }
+            Outcome::Failed => {
+                self.tx.update_state(db, State::Failed).await?;
+            }
+            Outcome::Retry => {
+                let config = CONFIG.get().unwrap();
+                let age = Local::now() - *self.tx.created_at();
+                if age > Duration::seconds(config.max_back_check_age()) {
+                    // 回查太久仍然没有确定结果，放弃这个消息
+                    self.tx.update_state(db, State::Failed).await?;
+                } else {
+                    // 结果不确定，每次回查的间隔加倍
+                    let delay = self.tx.retry_policy().next(self.tx.delay());
+                    self.tx.touch(db, delay).await?;
+                    return Ok(false);
+                }
+            }
+        }
         Ok(true)
     }
 }
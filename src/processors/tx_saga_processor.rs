@@ -1,13 +1,10 @@
-use std::collections::HashMap;
-
-use quaint::pooled::PooledConnection;
+use rocket::futures::future::join_all;
 
 use crate::errors;
 use crate::models::transaction::{Gid, State, Transaction, TransactionBranch};
+use crate::store::Store;
 
-use super::{Processor, SagaStep};
-
-type Conn = PooledConnection;
+use super::{handle_response, post, send, Processor, ProcessorType, Saga};
 
 #[derive(Debug)]
 pub struct TxSagaProcessor<'tx> {
@@ -22,8 +19,11 @@ impl<'tx> Processor<'tx> for TxSagaProcessor<'tx> {
     }
 
     fn branches(&self) -> Vec<TransactionBranch> {
-        let steps: Vec<SagaStep> = serde_json::from_str(self.tx.payload()).unwrap();
-        let mut branches = Vec::with_capacity(steps.len());
+        let steps = match self.tx.r#type() {
+            ProcessorType::Saga(saga) => saga.steps(),
+            _ => unreachable!(),
+        };
+        let mut branches = Vec::with_capacity(steps.len() * 2);
         for step in steps.iter() {
             // let branch_id = format!("{:02}", i + 1);
             let branch_id = Gid::new_v4();
@@ -34,7 +34,9 @@ impl<'tx> Processor<'tx> for TxSagaProcessor<'tx> {
                 State::Prepared,
                 step.on_reverting().to_string(),
                 step.payload.to_string(),
-            ));
+            )
+            .with_retry_policy(step.retry_policy.clone())
+            .with_options(step.options.clone()));
             branches.push(TransactionBranch::new(
                 self.tx.gid(),
                 branch_id,
@@ -42,97 +44,195 @@ impl<'tx> Processor<'tx> for TxSagaProcessor<'tx> {
                 State::Prepared,
                 step.on_committing().to_string(),
                 step.payload.to_string(),
-            ));
+            )
+            .with_retry_policy(step.retry_policy.clone())
+            .with_options(step.options.clone()));
         }
         branches
     }
 
-    async fn exec(&mut self, db: &Conn, branch: &TransactionBranch) -> Result<(), errors::Error> {
-        let cli = reqwest::Client::new();
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            reqwest::header::CONTENT_TYPE,
-            "application/json".try_into().unwrap(),
-        );
-        headers.insert(
-            reqwest::header::ACCEPT,
-            "application/json".try_into().unwrap(),
-        );
-        let resp = cli
-            .post(branch.url())
-            .query(&self.tx.branch_params(branch))
-            .body(branch.payload().to_string())
-            .headers(headers)
-            .send()
-            .await?;
-        /*
-        body := resp.String()
-        if strings.Contains(body, "SUCCESS") {
-            t.touch(db, config.TransCronInterval)
-            branch.changeStatus(db, "succeed")
-        } else if branch.BranchType == "action" && strings.Contains(body, "FAILURE") {
-            t.touch(db, config.TransCronInterval)
-            branch.changeStatus(db, "failed")
-        } else {
-            panic(fmt.Errorf("unknown response: %s, will be retried", body))
-        }
-        */
-        Ok(())
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error> {
+        let reply = send(self.request(branch)).await;
+        // 只有正向操作允许失败，补偿操作必须重试直到成功
+        let failable = branch.r#type() == "on_committing";
+        handle_response(self.tx, db, branch, reply, failable).await
     }
 
-    async fn once(&mut self, db: &Conn, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
-        match self.tx.state() {
-            State::Submitted => {}
-            _ => {
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error> {
+        let steps = match self.tx.r#type() {
+            ProcessorType::Saga(saga) => Step::pair(saga, branches),
+            _ => unreachable!(),
+        };
+        if self.tx.state() == State::Submitted {
+            if self.commit(db, branches, &steps).await? {
+                self.tx.update_state(db, State::Succeed).await?;
                 return Ok(());
             }
+            self.tx.update_state(db, State::Aborting).await?;
         }
-        let mut taken = 0;
-        let mut ok = true;
-        for branch in branches {
-            // if branch.BranchType != "action" || branch.Status != "prepared" {
-            match (branch.r#type(), branch.state()) {
-                ("action", State::Prepared) => {
-                    //
-                }
-                _ => {
-                    continue;
+        if self.tx.state() != State::Aborting {
+            return Ok(());
+        }
+        self.revert(db, branches, &steps).await?;
+        self.tx.update_state(db, State::Failed).await?;
+        Ok(())
+    }
+}
+
+/// Indexes of the two branches of one saga step, and the steps it depends on.
+#[derive(Debug)]
+struct Step {
+    branch_id: Gid,
+    on_committing: Option<usize>,
+    on_reverting: Option<usize>,
+    depends_on: Vec<usize>,
+}
+
+impl Step {
+    /// Pair up branches by `branch_id`, keeping the order they were saved.
+    fn pair(saga: &Saga, branches: &[TransactionBranch]) -> Vec<Step> {
+        let mut steps: Vec<Step> = Vec::new();
+        for (idx, branch) in branches.iter().enumerate() {
+            let pos = match steps.iter().position(|step| step.branch_id == branch.branch_id()) {
+                Some(pos) => pos,
+                None => {
+                    steps.push(Step {
+                        branch_id: branch.branch_id(),
+                        on_committing: None,
+                        on_reverting: None,
+                        depends_on: saga.dependencies(steps.len()),
+                    });
+                    steps.len() - 1
                 }
+            };
+            match branch.r#type() {
+                "on_committing" => steps[pos].on_committing = Some(idx),
+                "on_reverting" => steps[pos].on_reverting = Some(idx),
+                _ => {}
             }
-            self.exec(db, branch).await?;
-            match branch.state() {
-                State::Succeed => {
-                    taken += 1;
-                }
-                _ => {
-                    ok = false;
-                    break;
-                }
+        }
+        steps
+    }
+
+    fn committing(&self, branches: &[TransactionBranch]) -> Option<State> {
+        self.on_committing.map(|idx| branches[idx].state())
+    }
+
+    /// Whether `on_committing` has been called, an uncertain call may have taken
+    /// effect on the RM, so it has to be compensated as well.
+    fn attempted(&self, branches: &[TransactionBranch]) -> bool {
+        self.on_committing
+            .map(|idx| &branches[idx])
+            .map(|branch| branch.attempts() > 0 || matches!(branch.state(), State::Succeed | State::Failed))
+            .unwrap_or(false)
+    }
+
+    fn reverted(&self, branches: &[TransactionBranch]) -> bool {
+        !self.attempted(branches)
+            || self
+                .on_reverting
+                .map(|idx| branches[idx].state() == State::Succeed)
+                .unwrap_or(true)
+    }
+}
+
+impl<'tx> TxSagaProcessor<'tx> {
+    fn request(&self, branch: &TransactionBranch) -> reqwest::RequestBuilder {
+        post(self.tx, branch).body(branch.payload().to_string())
+    }
+
+    /// Call the given branches concurrently, then apply their responses one by one.
+    async fn exec_all(
+        &mut self,
+        db: &dyn Store,
+        branches: &mut [TransactionBranch],
+        ready: &[usize],
+    ) -> Result<(), errors::Error> {
+        if let [idx] = ready {
+            return self.exec(db, &mut branches[*idx]).await;
+        }
+        let requests = ready.iter().map(|&idx| send(self.request(&branches[idx])));
+        let replies = join_all(requests).await;
+        let mut first_err = None;
+        for (&idx, reply) in ready.iter().zip(replies) {
+            let branch = &mut branches[idx];
+            let failable = branch.r#type() == "on_committing";
+            if let Err(err) = handle_response(self.tx, db, branch, reply, failable).await {
+                first_err.get_or_insert(err);
             }
         }
-        if ok {
-            self.tx.update_state(db, State::Succeed).await?;
-            return Ok(());
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
         }
-        match self.tx.state() {
-            State::Aborting | State::Failed => {
-                //
+    }
+
+    /// Run `on_committing` branches whose dependencies are all succeed, wave by wave,
+    /// return false on the first definite failure, even if its siblings were uncertain.
+    ///
+    /// Succeed branches are skipped, so a restarted transaction resumes where it stopped.
+    async fn commit(
+        &mut self,
+        db: &dyn Store,
+        branches: &mut [TransactionBranch],
+        steps: &[Step],
+    ) -> Result<bool, errors::Error> {
+        loop {
+            if steps
+                .iter()
+                .any(|step| step.committing(branches) == Some(State::Failed))
+            {
+                return Ok(false);
             }
-            _ => {
-                self.tx.update_state(db, State::Aborting);
+            let ready: Vec<usize> = steps
+                .iter()
+                .filter(|step| step.committing(branches) == Some(State::Prepared))
+                .filter(|step| {
+                    step.depends_on
+                        .iter()
+                        .all(|&dep| steps[dep].committing(branches) == Some(State::Succeed))
+                })
+                .filter_map(|step| step.on_committing)
+                .collect();
+            // 依赖只能指向前面的步骤，没有可执行的步骤即表示全部完成
+            if ready.is_empty() {
+                return Ok(true);
             }
-        };
-        for branch in branches.iter().take(taken) {
-            match (branch.r#type(), branch.state()) {
-                ("compensate", State::Prepared) => {
-                    self.exec(db, branch).await;
-                }
-                _ => {
-                    continue;
+            if let Err(err) = self.exec_all(db, branches, &ready).await {
+                // 同一批中已有分支明确失败时直接回滚，结果不确定的分支随后一起补偿
+                let failed = ready.iter().any(|&idx| branches[idx].state() == State::Failed);
+                if !failed || !matches!(err, errors::Error::UnexpectedResponse(..)) {
+                    return Err(err);
                 }
             }
         }
-        self.tx.update_state(db, State::Failed).await?;
-        Ok(())
+    }
+
+    /// Run `on_reverting` branches of the attempted steps in reverse topological order,
+    /// a step is compensated only after all steps depending on it are compensated.
+    async fn revert(
+        &mut self,
+        db: &dyn Store,
+        branches: &mut [TransactionBranch],
+        steps: &[Step],
+    ) -> Result<(), errors::Error> {
+        loop {
+            let ready: Vec<usize> = steps
+                .iter()
+                .enumerate()
+                .filter(|(_, step)| !step.reverted(branches))
+                .filter(|(idx, _)| {
+                    steps
+                        .iter()
+                        .filter(|other| other.depends_on.contains(idx))
+                        .all(|other| other.reverted(branches))
+                })
+                .filter_map(|(_, step)| step.on_reverting)
+                .collect();
+            if ready.is_empty() {
+                return Ok(());
+            }
+            self.exec_all(db, branches, &ready).await?;
+        }
     }
 }
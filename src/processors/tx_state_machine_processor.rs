@@ -0,0 +1,126 @@
+use crate::errors;
+use crate::models::transaction::{State, Transaction, TransactionBranch};
+use crate::store::Store;
+
+use super::{handle_response, post, send, End, Outcome, Processor, ProcessorType, StateMachine};
+
+#[derive(Debug)]
+pub struct TxStateMachineProcessor<'tx> {
+    tx: &'tx mut Transaction,
+    // 本轮处理中新进入的状态的分支
+    entered: Vec<TransactionBranch>,
+}
+
+#[async_trait]
+impl<'tx> Processor<'tx> for TxStateMachineProcessor<'tx> {
+    fn with_transaction(tx: &'tx mut Transaction) -> Box<dyn Processor<'tx> + Send + 'tx>
+    where Self: Sized {
+        Box::new(TxStateMachineProcessor {
+            tx,
+            entered: Vec::new(),
+        })
+    }
+
+    fn branches(&self) -> Vec<TransactionBranch> {
+        let machine = self.machine();
+        machine.branches(self.tx.gid(), machine.initial())
+    }
+
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error> {
+        let reply = send(post(self.tx, branch).body(branch.payload().to_string())).await;
+        let is_action = branch.r#type() == "action";
+        if is_action && reply.outcome() == Outcome::Succeed {
+            // 标记当前动作成功与保存下一个状态在同一个事务里，重启后既不会丢失选择的分支，
+            // 也不会在当前动作未完成时从下一个状态继续
+            let machine = self.machine();
+            let gid = self.tx.gid();
+            let next = machine
+                .state_of(gid, branch.branch_id())
+                .and_then(|name| machine.state(name))
+                .and_then(|state| state.next(reply.body()))
+                .map(|name| machine.branches(gid, name))
+                .unwrap_or_default();
+            branch.advance(db, &next).await?;
+            self.entered.extend(next);
+        }
+        handle_response(self.tx, db, branch, reply, is_action).await
+    }
+
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error> {
+        let mut branches = branches.to_vec();
+        if self.tx.state() == State::Submitted {
+            match self.run(db, &mut branches).await? {
+                End::Succeed => {
+                    self.tx.update_state(db, State::Succeed).await?;
+                    return Ok(());
+                }
+                End::Failed => {
+                    self.tx.update_state(db, State::Aborting).await?;
+                }
+            }
+        }
+        if self.tx.state() != State::Aborting {
+            return Ok(());
+        }
+        // 按进入的相反顺序补偿调用过的状态，结果不确定的动作也可能已经生效
+        let attempted: Vec<_> = branches
+            .iter()
+            .filter(|branch| branch.r#type() == "action" && branch.attempts() > 0)
+            .map(|branch| branch.branch_id())
+            .collect();
+        for branch_id in attempted.into_iter().rev() {
+            let branch = branches.iter_mut().find(|branch| {
+                branch.branch_id() == branch_id && branch.r#type() == "compensation"
+            });
+            if let Some(branch) = branch {
+                if branch.state() == State::Prepared {
+                    self.exec(db, branch).await?;
+                }
+            }
+        }
+        self.tx.update_state(db, State::Failed).await?;
+        Ok(())
+    }
+}
+
+impl<'tx> TxStateMachineProcessor<'tx> {
+    fn machine(&self) -> &StateMachine {
+        match self.tx.r#type() {
+            ProcessorType::StateMachine(machine) => machine,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Execute actions from the latest entered state, until a terminal state is reached.
+    async fn run(
+        &mut self,
+        db: &dyn Store,
+        branches: &mut Vec<TransactionBranch>,
+    ) -> Result<End, errors::Error> {
+        loop {
+            // 分支按保存顺序排列，最后一个即当前状态
+            let idx = match branches.iter().rposition(|branch| branch.r#type() != "compensation") {
+                Some(idx) => idx,
+                None => return Ok(End::Failed),
+            };
+            let branch = &mut branches[idx];
+            match (branch.r#type(), branch.state()) {
+                ("end", _) => {
+                    let machine = self.machine();
+                    let end = machine
+                        .state_of(self.tx.gid(), branch.branch_id())
+                        .and_then(|name| machine.state(name))
+                        .and_then(|state| state.end());
+                    return Ok(end.unwrap_or(End::Failed));
+                }
+                (_, State::Failed) => return Ok(End::Failed),
+                (_, State::Prepared) => {
+                    self.exec(db, branch).await?;
+                    branches.append(&mut self.entered);
+                }
+                // 动作成功却没有进入下一个状态，只有定义被改动时才会出现
+                _ => return Ok(End::Failed),
+            }
+        }
+    }
+}
@@ -1,13 +1,8 @@
-use std::collections::HashMap;
-
-use quaint::pooled::PooledConnection;
-
 use crate::errors;
 use crate::models::transaction::{State, Transaction, TransactionBranch};
+use crate::store::Store;
 
-use super::Processor;
-
-type Conn = PooledConnection;
+use super::{handle_response, post, send, Processor};
 
 #[derive(Debug)]
 pub struct TxTCCProcessor<'tx> {
@@ -25,41 +20,14 @@ impl<'tx> Processor<'tx> for TxTCCProcessor<'tx> {
         Vec::new()
     }
 
-    async fn exec(&mut self, db: &Conn, branch: &TransactionBranch) -> Result<(), errors::Error> {
-        let cli = reqwest::Client::new();
-        let mut headers = reqwest::header::HeaderMap::new();
-        headers.insert(
-            reqwest::header::CONTENT_TYPE,
-            "application/json".try_into().unwrap(),
-        );
-        headers.insert(
-            reqwest::header::ACCEPT,
-            "application/json".try_into().unwrap(),
-        );
-        let resp = cli
-            .post(branch.url())
-            .body(branch.payload().to_string())
-            .headers(headers)
-            .send()
-            .await?
-            .json::<HashMap<String, serde_json::Value>>();
-        // resp, err := common.RestyClient.R().SetBody(branch.Data).SetHeader("Content-type", "application/json").SetQueryParams(t.getBranchParams(branch)).Post(branch.URL)
-        /*
-        body := resp.String()
-        if strings.Contains(body, "SUCCESS") {
-            t.touch(db, config.TransCronInterval)
-            branch.changeStatus(db, "succeed")
-        } else if branch.BranchType == "try" && strings.Contains(body, "FAILURE") {
-            t.touch(db, config.TransCronInterval)
-            branch.changeStatus(db, "failed")
-        } else {
-            panic(fmt.Errorf("unknown response: %s, will be retried", body))
-        }
-        */
-        Ok(())
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error> {
+        let reply = send(post(self.tx, branch).body(branch.payload().to_string())).await;
+        // confirm 和 cancel 不允许失败，只能重试
+        let failable = branch.r#type() == "try";
+        handle_response(self.tx, db, branch, reply, failable).await
     }
 
-    async fn once(&mut self, db: &Conn, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error> {
         let r#type = match self.tx.state() {
             State::Succeed | State::Failed => {
                 return Ok(());
@@ -67,9 +35,9 @@ impl<'tx> Processor<'tx> for TxTCCProcessor<'tx> {
             State::Submitted => "confirm",
             _ => "cancel",
         };
-        for branch in branches.iter().rev() {
-            if branch.r#type() == r#type {
-                self.exec(db, branch).await;
+        for branch in branches.iter_mut().rev() {
+            if branch.r#type() == r#type && branch.state() == State::Prepared {
+                self.exec(db, branch).await?;
             }
         }
         let state = match self.tx.state() {
@@ -77,7 +45,7 @@ impl<'tx> Processor<'tx> for TxTCCProcessor<'tx> {
             _ => State::Failed,
         };
         // 已全部处理完
-        self.tx.update_state(db, state);
+        self.tx.update_state(db, state).await?;
         Ok(())
     }
 }
@@ -1,12 +1,10 @@
-use quaint::pooled::PooledConnection;
 use serde::Serialize;
 
 use crate::errors;
 use crate::models::transaction::{State, Transaction, TransactionBranch};
+use crate::store::Store;
 
-use super::Processor;
-
-type Conn = PooledConnection;
+use super::{handle_response, post, send, Processor};
 
 #[derive(Debug)]
 pub struct TxXaProcessor<'tx> {
@@ -24,55 +22,44 @@ impl<'tx> Processor<'tx> for TxXaProcessor<'tx> {
         Vec::new()
     }
 
-    async fn exec(&mut self, db: &Conn, branch: &TransactionBranch) -> Result<(), errors::Error> {
+    async fn exec(&mut self, db: &dyn Store, branch: &mut TransactionBranch) -> Result<(), errors::Error> {
         #[derive(Debug, Serialize)]
         struct Payload {
             gid: uuid::Uuid,
             branch_id: uuid::Uuid,
             action: String,
         }
-        let mut paylaod = Payload {
+        let paylaod = Payload {
             branch_id: branch.branch_id(),
             gid: self.tx.gid(),
-            action: match self.tx.state() {
-                State::Prepared => "rollback".to_string(),
-                _ => "commit".to_string(),
-            },
+            action: branch.r#type().to_string(),
         };
-        let cli = reqwest::Client::new();
-        let resp = cli.post(branch.url()).json(&paylaod).send().await?;
-        /*
-        body := resp.String()
-        if strings.Contains(body, "SUCCESS") {
-            t.touch(db, config.TransCronInterval)
-            branch.changeStatus(db, "succeed")
-        } else {
-            panic(fmt.Errorf("bad response: %s", body))
-        }
-        */
-        Ok(())
+        let reply = send(post(self.tx, branch).json(&paylaod)).await;
+        // 分支已经 XA PREPARE 过，commit 和 rollback 都只能重试直到成功
+        handle_response(self.tx, db, branch, reply, false).await
     }
 
-    async fn once(&mut self, db: &Conn, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
-        let r#type = match self.tx.state() {
-            State::Succeed => {
+    async fn once(&mut self, db: &dyn Store, branches: &mut [TransactionBranch]) -> Result<(), errors::Error> {
+        let (r#type, state) = match self.tx.state() {
+            State::Submitted => ("commit", State::Succeed),
+            State::Aborting => ("rollback", State::Failed),
+            _ => {
                 return Ok(());
             }
-            State::Submitted => "commit",
-            _ => "rollback",
         };
-        for branch in branches {
-            match (branch.r#type() == r#type, branch.state()) {
-                (true, State::Succeed) => {
-                    self.exec(db, branch).await;
-                }
-                _ => {}
+        // 一个分支失败不影响其他分支的提交或回滚，全部确认之后才结束
+        let mut first_err = None;
+        for branch in branches.iter_mut() {
+            if branch.r#type() != r#type || branch.state() != State::Prepared {
+                continue;
+            }
+            if let Err(err) = self.exec(db, branch).await {
+                first_err.get_or_insert(err);
             }
         }
-        let state = match self.tx.state() {
-            State::Submitted => State::Succeed,
-            _ => State::Failed,
-        };
+        if let Some(err) = first_err {
+            return Err(err);
+        }
         self.tx.update_state(db, state).await?;
         Ok(())
     }
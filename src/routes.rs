@@ -1,14 +1,23 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::prelude::*;
 use rocket::tokio;
 use rocket::serde::{json::Json, uuid::Uuid};
 use rocket_versioning::Versioning;
 use serde::{Deserialize, Serialize};
+use tracing::error;
 
 use crate::processors::ProcessorType;
-use crate::config::Config;
 use crate::database::DB;
 use crate::errors::{self, ErrorResponse};
-use crate::models::transaction::{Gid, State, Transaction, TransactionBranch, TransactionCreation};
+use crate::models::transaction::{
+    Gid, State, TCCBranchCreation, Transaction, TransactionBranch, TransactionCreation,
+    XaBranchCreation,
+};
+use crate::models::Event;
 use crate::responder::DynResponse;
+use crate::store::{Cursor, SortBy, Store, TransactionQuery};
 
 #[get("/transactions/<gid>")]
 async fn fetch_transaction(
@@ -21,15 +30,152 @@ async fn fetch_transaction(
     Ok(DynResponse::new(Tx { tx, branches }))
 }
 
-#[post("/transactions", data = "<tx>")]
+/// State transitions, RM calls and retries of the transaction, oldest first.
+#[get("/transactions/<gid>/events")]
+async fn fetch_events(
+    _v: Versioning<1, 0>,
+    db: DB,
+    gid: Uuid,
+) -> Result<DynResponse<Vec<Event>>, errors::ErrorResponse> {
+    let tx = Transaction::load(gid, db.as_ref()).await?;
+    let events = db.as_ref().events(tx.gid()).await?;
+    Ok(DynResponse::new(events))
+}
+
+/// 每页默认和最多返回的事务数
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Filters of the transaction listing, times are RFC 3339.
+#[derive(Debug, FromForm)]
+struct ListParams {
+    state: Option<String>,
+    #[field(name = "type")]
+    processor: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    modified_after: Option<String>,
+    modified_before: Option<String>,
+    payload: Option<String>,
+    sort: Option<String>,
+    order: Option<String>,
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+fn parse_time(name: &str, value: Option<&String>) -> Result<Option<DateTime<Local>>, errors::Error> {
+    value
+        .map(|value| {
+            DateTime::parse_from_rfc3339(value)
+                .map(|at| at.with_timezone(&Local))
+                .map_err(|err| errors::Error::InvalidQuery(format!("{} `{}`: {}", name, value, err)))
+        })
+        .transpose()
+}
+
+impl ListParams {
+    fn into_query(self) -> Result<TransactionQuery, errors::Error> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+        let mut query = TransactionQuery::new(limit)
+            .with_created_between(
+                parse_time("created_after", self.created_after.as_ref())?,
+                parse_time("created_before", self.created_before.as_ref())?,
+            )
+            .with_modified_between(
+                parse_time("modified_after", self.modified_after.as_ref())?,
+                parse_time("modified_before", self.modified_before.as_ref())?,
+            );
+        if let Some(state) = self.state {
+            let state = State::from_tag(&state)
+                .ok_or_else(|| errors::Error::InvalidQuery(format!("unknown state `{}`", state)))?;
+            query = query.with_state(state);
+        }
+        if let Some(processor) = self.processor {
+            query = query.with_processor(processor);
+        }
+        if let Some(payload) = self.payload {
+            query = query.with_payload(payload);
+        }
+        let sort_by = match self.sort {
+            Some(sort) => SortBy::from_tag(&sort)
+                .ok_or_else(|| errors::Error::InvalidQuery(format!("unknown sort `{}`", sort)))?,
+            None => SortBy::CreatedAt,
+        };
+        let descending = match self.order.as_deref() {
+            None | Some("asc") => false,
+            Some("desc") => true,
+            Some(order) => return Err(errors::Error::InvalidQuery(format!("unknown order `{}`", order))),
+        };
+        query = query.with_sort(sort_by, descending);
+        if let Some(after) = self.after {
+            let cursor = Cursor::parse(&after)
+                .ok_or_else(|| errors::Error::InvalidQuery(format!("invalid cursor `{}`", after)))?;
+            query = query.with_after(cursor);
+        }
+        Ok(query)
+    }
+}
+
+/// List transactions page by page, pass `next` of a page as `after` to get the
+/// following one.
+#[get("/transactions?<params..>")]
+async fn list_transactions(
+    _v: Versioning<1, 0>,
+    db: DB,
+    params: ListParams,
+) -> Result<DynResponse<TxPage>, errors::ErrorResponse> {
+    let query = params.into_query().map_err(|err| ErrorResponse::new(err, 5060))?;
+    let transactions = db.as_ref().list(&query).await?;
+    let next = match transactions.last() {
+        Some(tx) if transactions.len() == query.limit() => Some(query.cursor(tx).to_string()),
+        _ => None,
+    };
+    // 一次查出整页的分支
+    let gids: Vec<Gid> = transactions.iter().map(|tx| tx.gid()).collect();
+    let mut grouped: HashMap<Gid, Vec<TransactionBranch>> = HashMap::new();
+    for branch in db.as_ref().branches_of(&gids).await? {
+        grouped.entry(branch.gid()).or_default().push(branch);
+    }
+    let page = transactions
+        .into_iter()
+        .map(|tx| {
+            let branches = grouped.remove(&tx.gid()).unwrap_or_default();
+            Tx { tx, branches }
+        })
+        .collect();
+    Ok(DynResponse::new(TxPage { transactions: page, next }))
+}
+
+/// Create a prepared transaction, or with `submit=true` create and submit a
+/// message or saga at once.
+#[post("/transactions?<submit>", data = "<tx>")]
 async fn create_transaction(
     _v: Versioning<1, 0>,
     db: DB,
+    submit: Option<bool>,
     tx: Json<TransactionCreation>,
 ) -> Result<String, errors::ErrorResponse> {
+    if let Err(err) = tx.validate() {
+        return Err(ErrorResponse::new(err, 5040));
+    }
+    let submit = submit.unwrap_or(false);
+    match tx.r#type() {
+        &ProcessorType::Xa(_) | &ProcessorType::TCC(_) if submit => {
+            let err = errors::Error::UnexpectedType(tx.r#type().tag().to_string(), State::Prepared.tag().to_string(), "it can not be submitted on creation!".to_string());
+            return Err(ErrorResponse::new(err, 5010));
+        }
+        _ => {}
+    }
     let mut tx = Transaction::from(tx.0);
-    tx.save(db.as_ref()).await?;
-    Ok(tx.gid().to_string())
+    if submit {
+        tx.submitted();
+    }
+    tx.save(db.as_ref()).await.map_err(|err| state_error(err, 5010))?;
+    let gid = tx.gid();
+    if submit {
+        spawn_process(tx, db.into()).await?;
+    }
+    Ok(gid.to_string())
 }
 
 #[get("/gid")]
@@ -50,9 +196,8 @@ async fn submit(_v: Versioning<1, 0>, db: DB, gid: Uuid) -> Result<String, error
         }
     }
     tx.submitted();
-    tx.save(db.as_ref()).await?;
-    let db = db.into();
-    tokio::task::spawn(async move { tx.process(&db).await.unwrap(); });
+    tx.save(db.as_ref()).await.map_err(|err| state_error(err, 5010))?;
+    spawn_process(tx, db.into()).await?;
     Ok("SUCCESS".to_string())
 }
 
@@ -69,27 +214,70 @@ async fn abort(_v: Versioning<1, 0>, db: DB, gid: Uuid) -> Result<String, errors
         }
     }
     match tx.state() {
-        State::Prepared | State::Aborting => {},
+        State::Prepared => {
+            tx.abort(db.as_ref(), "aborted by application")
+                .await
+                .map_err(|err| state_error(err, 5020))?;
+        }
+        State::Aborting => {},
         _ => {
             let err = errors::Error::UnexpectedType(tx.r#type().tag().to_string(), tx.state().tag().to_string(), "it is not abortable!".to_string());
             return Err(ErrorResponse::new(err, 5020));
         }
     }
-    tokio::task::spawn(async move { tx.process(db.as_ref()).await.unwrap() });
+    spawn_process(tx, db.into()).await?;
     Ok("SUCCESS".to_string())
 }
 
-#[post("/transactions/<gid>/branches", data = "<tb>")]
+/// Report a state changed by someone else with `code`, as the state check
+/// before the write would have.
+pub(crate) fn state_error(err: errors::Error, code: u16) -> ErrorResponse {
+    match err {
+        err @ errors::Error::CannotSubmitTransaction(..) | err @ errors::Error::StateChanged(..) => {
+            ErrorResponse::new(err, code)
+        }
+        err => err.into(),
+    }
+}
+
+/// Process the transaction in background if the lease is taken, otherwise the
+/// instance who owns it, or the cron after the lease expired, will handle it.
+pub(crate) async fn spawn_process(mut tx: Transaction, db: Arc<dyn Store>) -> Result<(), errors::Error> {
+    if !tx.acquire_lease(db.as_ref()).await? {
+        return Ok(());
+    }
+    tokio::task::spawn(async move {
+        if let Err(err) = tx.process(db.as_ref()).await {
+            error!("processing transaction {} failed: {}", tx.gid(), err);
+        }
+        if let Err(err) = tx.release_lease(db.as_ref()).await {
+            error!("releasing lease of transaction {} failed: {}", tx.gid(), err);
+        }
+    });
+    Ok(())
+}
+
+async fn register_branches(
+    tx: &mut Transaction,
+    db: &DB,
+    branches: &[TransactionBranch],
+) -> Result<(), errors::ErrorResponse> {
+    match tx.register_branches(db.as_ref(), branches).await {
+        Err(err @ errors::Error::BranchRegisterDenied(..)) => Err(ErrorResponse::new(err, 5030)),
+        result => Ok(result?),
+    }
+}
+
+#[post("/transactions/<gid>/branches", data = "<branch>")]
 async fn create_xa_branches(
     _v: Versioning<1, 0>,
     db: DB,
-    config: &rocket::State<Config>,
     gid: Uuid,
-    tb: Json<TransactionBranch>,
+    branch: Json<XaBranchCreation>,
 ) -> Result<String, errors::ErrorResponse> {
     let mut tx = Transaction::load(gid, db.as_ref()).await?;
-    match tx.state() {
-        State::Prepared => {
+    match (tx.r#type(), tx.state()) {
+        (&ProcessorType::Xa(_), State::Prepared) => {
             //
         }
         _ => {
@@ -97,27 +285,16 @@ async fn create_xa_branches(
             return Err(ErrorResponse::new(err, 5030));
         }
     }
-    let mut branches = vec![tb.clone(), tb.clone()];
-    branches[0].with_type("rollback".to_string());
-    branches[1].with_type("commit".to_string());
-    /*
-    db.Must().Clauses(clause.OnConflict{
-        DoNothing: true,
-    }).Create(branches)
-    */
-    tx.touch(db.as_ref(), config.delay).await?;
+    if let Err(err) = branch.validate() {
+        return Err(ErrorResponse::new(err, 5040));
+    }
+    let branches = branch.0.into_branches(gid);
+    register_branches(&mut tx, &db, &branches).await?;
+    let delay = tx.retry_policy().initial_interval();
+    tx.touch(db.as_ref(), delay).await?;
     Ok(gid.to_string())
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct TCCBranchCreation {
-    state: State,
-    payload: String,
-    cancel_url: String,
-    confirm_url: String,
-    r#try_url: String,
-}
-
 #[post("/transactions/<gid>/branches/<branch_id>/tcc", data = "<branch>")]
 async fn create_tcc_branches(
     _v: Versioning<1, 0>,
@@ -125,11 +302,10 @@ async fn create_tcc_branches(
     gid: Uuid,
     branch_id: Uuid,
     branch: Json<TCCBranchCreation>,
-    config: &rocket::State<Config>,
 ) -> Result<String, errors::ErrorResponse> {
     let mut tx = Transaction::load(gid, db.as_ref()).await?;
-    match tx.state() {
-        State::Prepared => {
+    match (tx.r#type(), tx.state()) {
+        (&ProcessorType::TCC(_), State::Prepared) => {
             //
         }
         _ => {
@@ -138,46 +314,13 @@ async fn create_tcc_branches(
         }
     }
 
-    let TCCBranchCreation {
-        cancel_url,
-        confirm_url,
-        try_url,
-        state,
-        payload,
-    } = branch.0;
-
-    let mut branches = Vec::with_capacity(3);
-    branches.push(TransactionBranch::new(
-        gid,
-        branch_id,
-        "cancel".to_string(),
-        state,
-        cancel_url,
-        payload.to_string(),
-    ));
-    branches.push(TransactionBranch::new(
-        gid,
-        branch_id,
-        "confirm".to_string(),
-        state,
-        confirm_url,
-        payload.to_string(),
-    ));
-    branches.push(TransactionBranch::new(
-        gid,
-        branch_id,
-        "try".to_string(),
-        state,
-        try_url,
-        payload.to_string(),
-    ));
-
-    /*
-    db.Must().Clauses(clause.OnConflict{
-        DoNothing: true,
-    }).Create(branches)
-    */
-    tx.touch(db.as_ref(), config.delay).await?;
+    if let Err(err) = branch.validate() {
+        return Err(ErrorResponse::new(err, 5040));
+    }
+    let branches = branch.0.into_branches(gid, branch_id);
+    register_branches(&mut tx, &db, &branches).await?;
+    let delay = tx.retry_policy().initial_interval();
+    tx.touch(db.as_ref(), delay).await?;
     Ok("SUCCESS".to_string())
 }
 
@@ -188,10 +331,18 @@ struct Tx {
     branches: Vec<TransactionBranch>,
 }
 
+#[derive(Debug, Serialize)]
+struct TxPage {
+    transactions: Vec<Tx>,
+    next: Option<String>,
+}
+
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         gid,
         fetch_transaction,
+        fetch_events,
+        list_transactions,
         create_transaction,
         create_tcc_branches,
         create_xa_branches,
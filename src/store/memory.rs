@@ -0,0 +1,320 @@
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use quaint::connector::ResultSet;
+use quaint::prelude::*;
+
+use crate::errors;
+use crate::models::transaction::{Gid, State, Transaction, TransactionBranch};
+use crate::models::{Audit, Event, Table};
+
+use super::{Store, TransactionQuery, BRANCH_COLUMNS, SCHEDULE_COLUMNS, STATE_COLUMNS};
+
+type Row = Vec<Value<'static>>;
+
+/// Store keeping rows in memory, for tests without a database.
+///
+/// Rows are written and read by the same mapping as [`super::SqlStore`].
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    transactions: Mutex<Vec<Row>>,
+    branches: Mutex<Vec<Row>>,
+    audits: Mutex<Vec<Row>>,
+    events: Mutex<Vec<Row>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+fn index<T: Table>(name: &str) -> usize {
+    T::columns().iter().position(|column| *column == name).unwrap()
+}
+
+fn get<'a, T: Table>(row: &'a Row, name: &str) -> &'a Value<'static> {
+    &row[index::<T>(name)]
+}
+
+fn read<T: Table>(rows: Vec<Row>) -> ResultSet {
+    let names = T::columns().into_iter().map(str::to_string).collect();
+    ResultSet::new(names, rows)
+}
+
+fn write<T: Table>(row: &mut Row, model: &T, columns: &[&'static str]) {
+    for (name, value) in model.values_of(columns) {
+        row[index::<T>(name)] = value;
+    }
+}
+
+fn is_tx(row: &Row, gid: Gid) -> bool {
+    *get::<Transaction>(row, "gid") == Value::from(gid)
+}
+
+fn is_branch(row: &Row, branch: &TransactionBranch) -> bool {
+    *get::<TransactionBranch>(row, "gid") == Value::from(branch.gid())
+        && *get::<TransactionBranch>(row, "branch_id") == Value::from(branch.branch_id())
+        && *get::<TransactionBranch>(row, "type") == Value::from(branch.r#type().to_string())
+}
+
+fn unleased(row: &Row, now: DateTime<Utc>) -> bool {
+    let current = get::<Transaction>(row, "owner").as_uuid();
+    let expires_at = get::<Transaction>(row, "lease_expires_at").as_datetime();
+    current.is_none() || expires_at.map(|at| at < now).unwrap_or(true)
+}
+
+fn leasable(row: &Row, owner: Gid, now: DateTime<Utc>) -> bool {
+    unleased(row, now) || get::<Transaction>(row, "owner").as_uuid() == Some(owner)
+}
+
+fn lease(row: &mut Row, owner: Gid, expires_at: DateTime<Local>) {
+    row[index::<Transaction>("owner")] = Value::from(owner);
+    row[index::<Transaction>("lease_expires_at")] = Value::from(expires_at.with_timezone(&Utc));
+}
+
+fn matches(tx: &Transaction, query: &TransactionQuery) -> bool {
+    let created_at = *tx.created_at();
+    let last_modified = *tx.last_modified();
+    query.state.map(|state| tx.state() == state).unwrap_or(true)
+        && query.processor.as_deref().map(|p| tx.r#type().tag() == p).unwrap_or(true)
+        && query.created_after.map(|at| created_at >= at).unwrap_or(true)
+        && query.created_before.map(|at| created_at < at).unwrap_or(true)
+        && query.modified_after.map(|at| last_modified >= at).unwrap_or(true)
+        && query.modified_before.map(|at| last_modified < at).unwrap_or(true)
+        && query.payload.as_deref().map(|p| tx.payload().contains(p)).unwrap_or(true)
+}
+
+fn insert_branches(rows: &mut Vec<Row>, branches: &[TransactionBranch]) {
+    for branch in branches {
+        if !rows.iter().any(|row| is_branch(row, branch)) {
+            rows.push(branch.values());
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn create(&self, tx: &Transaction, branches: &[TransactionBranch]) -> Result<bool, errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        if transactions.iter().any(|row| is_tx(row, tx.gid())) {
+            return Ok(false);
+        }
+        transactions.push(tx.values());
+        insert_branches(&mut self.branches.lock().unwrap(), branches);
+        Ok(true)
+    }
+
+    async fn load(&self, gid: Gid) -> Result<Option<Transaction>, errors::Error> {
+        let rows = self
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| is_tx(row, gid))
+            .cloned()
+            .collect();
+        Transaction::from_first(read::<Transaction>(rows))
+    }
+
+    async fn list(&self, query: &TransactionQuery) -> Result<Vec<Transaction>, errors::Error> {
+        let rows = self.transactions.lock().unwrap().clone();
+        let mut transactions: Vec<Transaction> = Transaction::from_rows(read::<Transaction>(rows))?
+            .into_iter()
+            .filter(|tx| matches(tx, query))
+            .collect();
+        let key = |tx: &Transaction| (query.sort_by.value(tx), tx.gid());
+        transactions.sort_by_key(key);
+        if query.descending {
+            transactions.reverse();
+        }
+        if let Some(after) = query.after {
+            let cursor = (after.value, after.gid);
+            transactions.retain(|tx| if query.descending { key(tx) < cursor } else { key(tx) > cursor });
+        }
+        transactions.truncate(query.limit);
+        Ok(transactions)
+    }
+
+    async fn branches(&self, gid: Gid) -> Result<Vec<TransactionBranch>, errors::Error> {
+        let rows = self
+            .branches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| *get::<TransactionBranch>(row, "gid") == Value::from(gid))
+            .cloned()
+            .collect();
+        TransactionBranch::from_rows(read::<TransactionBranch>(rows))
+    }
+
+    async fn branches_of(&self, gids: &[Gid]) -> Result<Vec<TransactionBranch>, errors::Error> {
+        let gids: Vec<Value> = gids.iter().map(|&gid| Value::from(gid)).collect();
+        let rows = self
+            .branches
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| gids.contains(get::<TransactionBranch>(row, "gid")))
+            .cloned()
+            .collect();
+        TransactionBranch::from_rows(read::<TransactionBranch>(rows))
+    }
+
+    async fn update_state(&self, tx: &Transaction, expected: State) -> Result<bool, errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let row = transactions
+            .iter_mut()
+            .filter(|row| is_tx(row, tx.gid()))
+            .find(|row| *get::<Transaction>(row, "state") == Value::from(expected));
+        match row {
+            Some(row) => {
+                write(row, tx, STATE_COLUMNS);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn touch(&self, tx: &Transaction) -> Result<(), errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        if let Some(row) = transactions.iter_mut().find(|row| is_tx(row, tx.gid())) {
+            write(row, tx, SCHEDULE_COLUMNS);
+        }
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, gid: Gid, owner: Gid, expires_at: DateTime<Local>) -> Result<bool, errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        match transactions.iter_mut().find(|row| is_tx(row, gid)) {
+            Some(row) if leasable(row, owner, Utc::now()) => {
+                lease(row, owner, expires_at);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn release_lease(&self, gid: Gid, owner: Gid) -> Result<(), errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let owned = transactions
+            .iter_mut()
+            .filter(|row| is_tx(row, gid))
+            .find(|row| get::<Transaction>(row, "owner").as_uuid() == Some(owner));
+        if let Some(row) = owned {
+            row[index::<Transaction>("owner")] = Value::Uuid(None);
+            row[index::<Transaction>("lease_expires_at")] = Value::DateTime(None);
+        }
+        Ok(())
+    }
+
+    async fn claim_due(&self, owner: Gid, expires_at: DateTime<Local>) -> Result<Option<Transaction>, errors::Error> {
+        let mut transactions = self.transactions.lock().unwrap();
+        let now = Utc::now();
+        let in_progress = [State::Prepared, State::Submitted, State::Aborting].map(Value::from);
+        let due = transactions
+            .iter_mut()
+            .filter(|row| in_progress.contains(get::<Transaction>(row, "state")))
+            .filter(|row| unleased(row, now))
+            .filter_map(|row| {
+                let scheduled_at = get::<Transaction>(row, "scheduled_at").as_datetime()?;
+                Some((scheduled_at, row))
+            })
+            .filter(|(scheduled_at, _)| *scheduled_at < now)
+            .min_by_key(|(scheduled_at, _)| *scheduled_at);
+        match due {
+            Some((_, row)) => {
+                lease(row, owner, expires_at);
+                Transaction::from_first(read::<Transaction>(vec![row.clone()]))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn oldest_due(&self) -> Result<Option<DateTime<Local>>, errors::Error> {
+        let now = Utc::now();
+        let in_progress = [State::Prepared, State::Submitted, State::Aborting].map(Value::from);
+        let oldest = self
+            .transactions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| in_progress.contains(get::<Transaction>(row, "state")))
+            .filter_map(|row| get::<Transaction>(row, "scheduled_at").as_datetime())
+            .filter(|scheduled_at| *scheduled_at < now)
+            .min();
+        Ok(oldest.map(|at| at.with_timezone(&Local)))
+    }
+
+    async fn insert_branches(&self, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+        insert_branches(&mut self.branches.lock().unwrap(), branches);
+        Ok(())
+    }
+
+    async fn register_branches(&self, gid: Gid, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+        // 持有事务表的锁，注册与提交、回滚互斥
+        let transactions = self.transactions.lock().unwrap();
+        let state = transactions
+            .iter()
+            .find(|row| is_tx(row, gid))
+            .and_then(|row| get::<Transaction>(row, "state").as_i64())
+            .and_then(State::from_i64);
+        if state != Some(State::Prepared) {
+            let state = state.map(|state| state.tag().to_string()).unwrap_or_default();
+            return Err(errors::Error::BranchRegisterDenied(gid, state));
+        }
+        insert_branches(&mut self.branches.lock().unwrap(), branches);
+        Ok(())
+    }
+
+    async fn update_branch(&self, branch: &TransactionBranch) -> Result<(), errors::Error> {
+        let mut rows = self.branches.lock().unwrap();
+        if let Some(row) = rows.iter_mut().find(|row| is_branch(row, branch)) {
+            write(row, branch, BRANCH_COLUMNS);
+        }
+        Ok(())
+    }
+
+    async fn advance_branch(&self, branch: &TransactionBranch, next: &[TransactionBranch]) -> Result<(), errors::Error> {
+        let mut rows = self.branches.lock().unwrap();
+        if let Some(row) = rows.iter_mut().find(|row| is_branch(row, branch)) {
+            write(row, branch, BRANCH_COLUMNS);
+        }
+        insert_branches(&mut rows, next);
+        Ok(())
+    }
+
+    async fn insert_audit(&self, audit: &Audit) -> Result<(), errors::Error> {
+        self.audits.lock().unwrap().push(audit.values());
+        Ok(())
+    }
+
+    async fn audits(&self, gid: Gid) -> Result<Vec<Audit>, errors::Error> {
+        let rows = self
+            .audits
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| *get::<Audit>(row, "gid") == Value::from(gid))
+            .cloned()
+            .collect();
+        Audit::from_rows(read::<Audit>(rows))
+    }
+
+    async fn insert_event(&self, event: &Event) -> Result<(), errors::Error> {
+        self.events.lock().unwrap().push(event.values());
+        Ok(())
+    }
+
+    async fn events(&self, gid: Gid) -> Result<Vec<Event>, errors::Error> {
+        let rows = self
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|row| *get::<Event>(row, "gid") == Value::from(gid))
+            .cloned()
+            .collect();
+        Event::from_rows(read::<Event>(rows))
+    }
+}
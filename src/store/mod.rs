@@ -0,0 +1,120 @@
+use chrono::prelude::*;
+
+use crate::errors;
+use crate::models::transaction::{Gid, State, Transaction, TransactionBranch};
+use crate::models::{Audit, Event};
+
+mod memory;
+mod query;
+mod sql;
+
+pub use memory::MemoryStore;
+pub use query::{Cursor, SortBy, TransactionQuery};
+pub use sql::SqlStore;
+
+/// The database behind a url, each one needs its cargo feature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Postgresql,
+    Mysql,
+    Sqlite,
+}
+
+impl Dialect {
+    pub fn from_url(database_url: &str) -> Result<Dialect, errors::Error> {
+        let scheme = database_url.split(':').next().unwrap_or_default();
+        match scheme {
+            #[cfg(feature = "postgresql")]
+            "postgres" | "postgresql" => Ok(Dialect::Postgresql),
+            #[cfg(feature = "mysql")]
+            "mysql" => Ok(Dialect::Mysql),
+            #[cfg(feature = "sqlite")]
+            "file" | "sqlite" => Ok(Dialect::Sqlite),
+            _ => Err(errors::Error::UnsupportedDatabase(scheme.to_string())),
+        }
+    }
+}
+
+/// Columns written by [`Store::update_state`].
+const STATE_COLUMNS: &[&str] = &[
+    "state",
+    "reason",
+    "delay",
+    "scheduled_at",
+    "committed_at",
+    "finished_at",
+    "rollbacked_at",
+    "last_modified",
+];
+
+/// Columns written by [`Store::touch`].
+const SCHEDULE_COLUMNS: &[&str] = &["delay", "scheduled_at"];
+
+/// Columns written by [`Store::update_branch`].
+const BRANCH_COLUMNS: &[&str] = &["state", "attempts", "finished_at", "rollbacked_at"];
+
+/// Where transactions and their branches are persisted.
+///
+/// Models, processors and routes only talk to this trait, [`SqlStore`] is
+/// used by the service and [`MemoryStore`] by tests.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Save a new transaction with its branches, return false if the gid exists.
+    async fn create(&self, tx: &Transaction, branches: &[TransactionBranch]) -> Result<bool, errors::Error>;
+
+    async fn load(&self, gid: Gid) -> Result<Option<Transaction>, errors::Error>;
+
+    /// One page of transactions matching the query.
+    async fn list(&self, query: &TransactionQuery) -> Result<Vec<Transaction>, errors::Error>;
+
+    /// Branches of the transaction, in the order they were saved.
+    async fn branches(&self, gid: Gid) -> Result<Vec<TransactionBranch>, errors::Error>;
+
+    /// Branches of all the transactions at once, in the order they were saved.
+    async fn branches_of(&self, gids: &[Gid]) -> Result<Vec<TransactionBranch>, errors::Error>;
+
+    /// Write the state of the transaction, with its reason, schedule, finish and modification time,
+    /// only if it is still `expected`, return false otherwise.
+    async fn update_state(&self, tx: &Transaction, expected: State) -> Result<bool, errors::Error>;
+
+    /// Write when the transaction is due.
+    async fn touch(&self, tx: &Transaction) -> Result<(), errors::Error>;
+
+    /// Take or renew the lease, return false if it is owned by another `owner`
+    /// whose lease has not expired yet.
+    async fn acquire_lease(&self, gid: Gid, owner: Gid, expires_at: DateTime<Local>) -> Result<bool, errors::Error>;
+
+    /// Give the lease back if it is still owned by `owner`.
+    async fn release_lease(&self, gid: Gid, owner: Gid) -> Result<(), errors::Error>;
+
+    /// Take the lease of the earliest due transaction in progress, which is not
+    /// leased or whose lease has expired.
+    async fn claim_due(&self, owner: Gid, expires_at: DateTime<Local>) -> Result<Option<Transaction>, errors::Error>;
+
+    /// When the earliest due transaction in progress was scheduled, leased or not.
+    async fn oldest_due(&self) -> Result<Option<DateTime<Local>>, errors::Error>;
+
+    /// Save branches, those already saved are left untouched.
+    async fn insert_branches(&self, branches: &[TransactionBranch]) -> Result<(), errors::Error>;
+
+    /// Save branches of a prepared transaction, the state is checked atomically
+    /// so registering can not race with submitting or aborting.
+    async fn register_branches(&self, gid: Gid, branches: &[TransactionBranch]) -> Result<(), errors::Error>;
+
+    /// Write the state, attempts and finish time of the branch.
+    async fn update_branch(&self, branch: &TransactionBranch) -> Result<(), errors::Error>;
+
+    /// Write the branch and save the branches it leads to in one go, so a crash
+    /// can not leave the next branches saved while the branch is still pending.
+    async fn advance_branch(&self, branch: &TransactionBranch, next: &[TransactionBranch]) -> Result<(), errors::Error>;
+
+    async fn insert_audit(&self, audit: &Audit) -> Result<(), errors::Error>;
+
+    /// Audits of the transaction, oldest first.
+    async fn audits(&self, gid: Gid) -> Result<Vec<Audit>, errors::Error>;
+
+    async fn insert_event(&self, event: &Event) -> Result<(), errors::Error>;
+
+    /// Events of the transaction, in the order they happened.
+    async fn events(&self, gid: Gid) -> Result<Vec<Event>, errors::Error>;
+}
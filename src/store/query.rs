@@ -0,0 +1,159 @@
+use std::fmt;
+
+use chrono::prelude::*;
+
+use crate::models::transaction::{Gid, State, Transaction};
+
+/// The column transactions are listed by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    CreatedAt,
+    LastModified,
+}
+
+impl SortBy {
+    pub fn from_tag(tag: &str) -> Option<SortBy> {
+        match tag {
+            "created_at" => Some(SortBy::CreatedAt),
+            "last_modified" => Some(SortBy::LastModified),
+            _ => None,
+        }
+    }
+
+    pub(super) fn column(&self) -> &'static str {
+        match self {
+            SortBy::CreatedAt => "created_at",
+            SortBy::LastModified => "last_modified",
+        }
+    }
+
+    pub(super) fn value(&self, tx: &Transaction) -> DateTime<Utc> {
+        match self {
+            SortBy::CreatedAt => tx.created_at().with_timezone(&Utc),
+            SortBy::LastModified => tx.last_modified().with_timezone(&Utc),
+        }
+    }
+}
+
+/// Where a page ends: the sort value and gid of its last transaction.
+///
+/// Formatted as `<nanoseconds since epoch>_<gid>`, it stays valid even if that
+/// transaction is modified or removed later.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub(super) value: DateTime<Utc>,
+    pub(super) gid: Gid,
+}
+
+impl Cursor {
+    pub fn parse(cursor: &str) -> Option<Cursor> {
+        let (nanos, gid) = cursor.split_once('_')?;
+        Some(Cursor {
+            value: Utc.timestamp_nanos(nanos.parse().ok()?),
+            gid: gid.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.value.timestamp_nanos(), self.gid)
+    }
+}
+
+/// Filters, order and page of [`super::Store::list`].
+///
+/// Pages are keyset paginated: `after` is the cursor of the last transaction of
+/// the previous page, transactions with the same sort value are ordered by gid.
+#[derive(Debug, Clone)]
+pub struct TransactionQuery {
+    pub(super) state: Option<State>,
+    pub(super) processor: Option<String>,
+    pub(super) created_after: Option<DateTime<Local>>,
+    pub(super) created_before: Option<DateTime<Local>>,
+    pub(super) modified_after: Option<DateTime<Local>>,
+    pub(super) modified_before: Option<DateTime<Local>>,
+    pub(super) payload: Option<String>,
+    pub(super) sort_by: SortBy,
+    pub(super) descending: bool,
+    pub(super) after: Option<Cursor>,
+    pub(super) limit: usize,
+}
+
+impl TransactionQuery {
+    pub fn new(limit: usize) -> TransactionQuery {
+        TransactionQuery {
+            state: None,
+            processor: None,
+            created_after: None,
+            created_before: None,
+            modified_after: None,
+            modified_before: None,
+            payload: None,
+            sort_by: SortBy::CreatedAt,
+            descending: false,
+            after: None,
+            limit,
+        }
+    }
+
+    pub fn with_state(mut self, state: State) -> TransactionQuery {
+        self.state = Some(state);
+        self
+    }
+
+    /// Only transactions of the processor type, `xa`, `tcc`, `saga`, etc.
+    pub fn with_processor(mut self, processor: String) -> TransactionQuery {
+        self.processor = Some(processor);
+        self
+    }
+
+    pub fn with_created_between(
+        mut self,
+        after: Option<DateTime<Local>>,
+        before: Option<DateTime<Local>>,
+    ) -> TransactionQuery {
+        self.created_after = after;
+        self.created_before = before;
+        self
+    }
+
+    pub fn with_modified_between(
+        mut self,
+        after: Option<DateTime<Local>>,
+        before: Option<DateTime<Local>>,
+    ) -> TransactionQuery {
+        self.modified_after = after;
+        self.modified_before = before;
+        self
+    }
+
+    /// Only transactions whose payload contains `payload`.
+    pub fn with_payload(mut self, payload: String) -> TransactionQuery {
+        self.payload = Some(payload);
+        self
+    }
+
+    pub fn with_sort(mut self, sort_by: SortBy, descending: bool) -> TransactionQuery {
+        self.sort_by = sort_by;
+        self.descending = descending;
+        self
+    }
+
+    pub fn with_after(mut self, after: Cursor) -> TransactionQuery {
+        self.after = Some(after);
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The cursor to pass as `after` for the page following `tx`.
+    pub fn cursor(&self, tx: &Transaction) -> Cursor {
+        Cursor {
+            value: self.sort_by.value(tx),
+            gid: tx.gid(),
+        }
+    }
+}
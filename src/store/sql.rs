@@ -0,0 +1,387 @@
+use std::ops::Deref;
+
+use chrono::prelude::*;
+use quaint::pooled::{PooledConnection, Quaint};
+use quaint::prelude::*;
+
+use crate::errors;
+use crate::metrics::ConnectionInUse;
+use crate::models::transaction::{Gid, State, Transaction, TransactionBranch};
+use crate::models::{mapping, Audit, Event, Table};
+
+use super::{Dialect, Store, TransactionQuery, BRANCH_COLUMNS, SCHEDULE_COLUMNS, STATE_COLUMNS};
+
+/// 每次最多尝试认领的到期事务数
+const CLAIM_CANDIDATES: usize = 8;
+
+/// Store backed by a pooled connection to PostgreSQL, MySQL or SQLite.
+///
+/// Portable SQL is used wherever possible, so the same queries work with every
+/// dialect enabled by cargo features.
+#[derive(Clone)]
+pub struct SqlStore {
+    pool: Quaint,
+    dialect: Dialect,
+}
+
+impl SqlStore {
+    pub fn new(pool: Quaint, dialect: Dialect) -> SqlStore {
+        SqlStore { pool, dialect }
+    }
+
+    pub fn connect(database_url: &str) -> Result<SqlStore, errors::Error> {
+        let dialect = Dialect::from_url(database_url)?;
+        let mut builder = Quaint::builder(database_url)?;
+        builder.test_on_check_out(true);
+        Ok(SqlStore::new(builder.build(), dialect))
+    }
+
+    async fn check_out(&self) -> Result<Connection, errors::Error> {
+        let conn = self.pool.check_out().await?;
+        Ok(Connection {
+            conn,
+            _in_use: ConnectionInUse::new(),
+        })
+    }
+}
+
+/// A pooled connection, counted as in use until it is returned.
+struct Connection {
+    conn: PooledConnection,
+    _in_use: ConnectionInUse,
+}
+
+impl Deref for Connection {
+    type Target = PooledConnection;
+
+    fn deref(&self) -> &PooledConnection {
+        &self.conn
+    }
+}
+
+async fn insert_branches<Q>(db: &Q, branches: &[TransactionBranch]) -> Result<(), errors::Error>
+where
+    Q: Queryable + ?Sized,
+{
+    if branches.is_empty() {
+        return Ok(());
+    }
+    let mut insertion = Insert::multi_into(TransactionBranch::tablename(), TransactionBranch::columns());
+    for branch in branches.iter() {
+        insertion = insertion.values(branch.values());
+    }
+    db.insert(insertion.build().on_conflict(OnConflict::DoNothing))
+        .await?;
+    Ok(())
+}
+
+async fn update_branch<Q>(db: &Q, branch: &TransactionBranch) -> Result<(), errors::Error>
+where
+    Q: Queryable + ?Sized,
+{
+    db.update(
+        update(branch, BRANCH_COLUMNS).so_that(
+            "gid"
+                .equals(branch.gid())
+                .and("branch_id".equals(branch.branch_id()))
+                .and("type".equals(branch.r#type())),
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// `column` contains `text` literally, like `str::contains`.
+fn contains(dialect: Dialect, column: &'static str, text: &str) -> Compare<'static> {
+    match dialect {
+        // sqlite 的 LIKE 没有默认的转义字符，改用 GLOB，同时也是区分大小写的
+        Dialect::Sqlite => {
+            let mut pattern = String::from("*");
+            for c in text.chars() {
+                match c {
+                    '*' | '?' | '[' => pattern.extend(['[', c, ']']),
+                    _ => pattern.push(c),
+                }
+            }
+            pattern.push('*');
+            column.compare_raw("GLOB", pattern)
+        }
+        // 反斜杠是 postgresql 和 mysql 默认的转义字符
+        _ => {
+            let mut pattern = String::from("%");
+            for c in text.chars() {
+                if matches!(c, '%' | '_' | '\\') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            pattern.push('%');
+            column.like(pattern)
+        }
+    }
+}
+
+/// Due transactions in progress.
+fn due(now: DateTime<Utc>) -> ConditionTree<'static> {
+    let in_progress = vec![
+        Value::from(State::Prepared),
+        Value::from(State::Submitted),
+        Value::from(State::Aborting),
+    ];
+    "scheduled_at".less_than(now).and("state".in_selection(in_progress))
+}
+
+/// Due transactions in progress, which are not leased or whose lease has expired.
+fn claimable(now: DateTime<Utc>) -> ConditionTree<'static> {
+    due(now).and("owner".is_null().or("lease_expires_at".less_than(now)))
+}
+
+fn update<'a, T: Table>(model: &T, columns: &[&'static str]) -> Update<'a> {
+    let mut x = Update::table(T::tablename());
+    for (name, value) in model.values_of(columns) {
+        x = x.set(name, value);
+    }
+    x
+}
+
+#[async_trait]
+impl Store for SqlStore {
+    async fn create(&self, tx: &Transaction, branches: &[TransactionBranch]) -> Result<bool, errors::Error> {
+        let conn = self.check_out().await?;
+        let db = conn.start_transaction().await?;
+        let insertion = Insert::multi_into(Transaction::tablename(), Transaction::columns())
+            .values(tx.values())
+            .build()
+            .on_conflict(OnConflict::DoNothing);
+        let inserted = db.execute(insertion.into()).await? > 0;
+        if inserted {
+            insert_branches(&db, branches).await?;
+        }
+        db.commit().await?;
+        Ok(inserted)
+    }
+
+    async fn load(&self, gid: Gid) -> Result<Option<Transaction>, errors::Error> {
+        let db = self.check_out().await?;
+        let q = Select::from_table(Transaction::tablename())
+            .so_that("gid".equals(gid))
+            .limit(1);
+        Transaction::from_first(db.select(q).await?)
+    }
+
+    async fn list(&self, query: &TransactionQuery) -> Result<Vec<Transaction>, errors::Error> {
+        let db = self.check_out().await?;
+        let column = query.sort_by.column();
+        let mut conditions: Vec<Expression> = Vec::new();
+        if let Some(state) = query.state {
+            conditions.push("state".equals(state).into());
+        }
+        if let Some(processor) = &query.processor {
+            conditions.push("processor".equals(processor.clone()).into());
+        }
+        if let Some(at) = query.created_after {
+            conditions.push("created_at".greater_than_or_equals(at.with_timezone(&Utc)).into());
+        }
+        if let Some(at) = query.created_before {
+            conditions.push("created_at".less_than(at.with_timezone(&Utc)).into());
+        }
+        if let Some(at) = query.modified_after {
+            conditions.push("last_modified".greater_than_or_equals(at.with_timezone(&Utc)).into());
+        }
+        if let Some(at) = query.modified_before {
+            conditions.push("last_modified".less_than(at.with_timezone(&Utc)).into());
+        }
+        if let Some(payload) = &query.payload {
+            conditions.push(contains(self.dialect, "payload", payload).into());
+        }
+        if let Some(after) = query.after {
+            let (value, gid) = (after.value, after.gid);
+            // (column, gid) 大于（或小于）上一页最后一条
+            let keyset = if query.descending {
+                column
+                    .less_than(value)
+                    .or(column.equals(value).and("gid".less_than(gid)))
+            } else {
+                column
+                    .greater_than(value)
+                    .or(column.equals(value).and("gid".greater_than(gid)))
+            };
+            conditions.push(keyset.into());
+        }
+        let mut q = Select::from_table(Transaction::tablename());
+        if !conditions.is_empty() {
+            q = q.so_that(ConditionTree::And(conditions));
+        }
+        let q = if query.descending {
+            q.order_by(column.descend()).order_by("gid".descend())
+        } else {
+            q.order_by(column.ascend()).order_by("gid".ascend())
+        };
+        Transaction::from_rows(db.select(q.limit(query.limit)).await?)
+    }
+
+    async fn branches(&self, gid: Gid) -> Result<Vec<TransactionBranch>, errors::Error> {
+        let db = self.check_out().await?;
+        let q = Select::from_table(TransactionBranch::tablename())
+            .so_that("gid".equals(gid))
+            .order_by("id".ascend());
+        TransactionBranch::from_rows(db.select(q).await?)
+    }
+
+    async fn branches_of(&self, gids: &[Gid]) -> Result<Vec<TransactionBranch>, errors::Error> {
+        if gids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let db = self.check_out().await?;
+        let gids: Vec<Value> = gids.iter().map(|&gid| Value::from(gid)).collect();
+        let q = Select::from_table(TransactionBranch::tablename())
+            .so_that("gid".in_selection(gids))
+            .order_by("id".ascend());
+        TransactionBranch::from_rows(db.select(q).await?)
+    }
+
+    async fn update_state(&self, tx: &Transaction, expected: State) -> Result<bool, errors::Error> {
+        let db = self.check_out().await?;
+        let x = update(tx, STATE_COLUMNS).so_that("gid".equals(tx.gid()).and("state".equals(expected)));
+        Ok(db.execute(x.into()).await? > 0)
+    }
+
+    async fn touch(&self, tx: &Transaction) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        db.update(update(tx, SCHEDULE_COLUMNS).so_that("gid".equals(tx.gid())))
+            .await?;
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, gid: Gid, owner: Gid, expires_at: DateTime<Local>) -> Result<bool, errors::Error> {
+        let db = self.check_out().await?;
+        let now: DateTime<Utc> = Utc::now();
+        let x = Update::table(Transaction::tablename())
+            .set("owner", owner)
+            .set("lease_expires_at", expires_at.with_timezone(&Utc))
+            .so_that(
+                "gid".equals(gid).and(
+                    "owner"
+                        .is_null()
+                        .or("owner".equals(owner))
+                        .or("lease_expires_at".less_than(now)),
+                ),
+            );
+        Ok(db.execute(x.into()).await? > 0)
+    }
+
+    async fn release_lease(&self, gid: Gid, owner: Gid) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        let x = Update::table(Transaction::tablename())
+            .set("owner", Value::Uuid(None))
+            .set("lease_expires_at", Value::DateTime(None))
+            .so_that("gid".equals(gid).and("owner".equals(owner)));
+        db.update(x).await?;
+        Ok(())
+    }
+
+    async fn claim_due(&self, owner: Gid, expires_at: DateTime<Local>) -> Result<Option<Transaction>, errors::Error> {
+        let db = self.check_out().await?;
+        let now = Utc::now();
+        let q = Select::from_table(Transaction::tablename())
+            .column("gid")
+            .so_that(claimable(now))
+            .order_by("scheduled_at".ascend())
+            .limit(CLAIM_CANDIDATES);
+        for row in db.select(q).await?.into_iter() {
+            let gid = mapping::uuid(&row, "gid")?;
+            // 条件更新，多个实例同时认领时只有一个会成功
+            let x = Update::table(Transaction::tablename())
+                .set("owner", owner)
+                .set("lease_expires_at", expires_at.with_timezone(&Utc))
+                .so_that("gid".equals(gid).and(claimable(now)));
+            if db.execute(x.into()).await? > 0 {
+                let q = Select::from_table(Transaction::tablename()).so_that("gid".equals(gid));
+                return Transaction::from_first(db.select(q).await?);
+            }
+        }
+        Ok(None)
+    }
+
+    async fn oldest_due(&self) -> Result<Option<DateTime<Local>>, errors::Error> {
+        let db = self.check_out().await?;
+        let q = Select::from_table(Transaction::tablename())
+            .column("scheduled_at")
+            .so_that(due(Utc::now()))
+            .order_by("scheduled_at".ascend())
+            .limit(1);
+        match db.select(q).await?.into_iter().next() {
+            Some(row) => mapping::opt_datetime(&row, "scheduled_at"),
+            None => Ok(None),
+        }
+    }
+
+    async fn insert_branches(&self, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        insert_branches(&*db, branches).await
+    }
+
+    async fn register_branches(&self, gid: Gid, branches: &[TransactionBranch]) -> Result<(), errors::Error> {
+        let conn = self.check_out().await?;
+        let db = conn.start_transaction().await?;
+        // 仅当仍是 prepared 时更新，同时锁住这一行，注册与提交、回滚互斥
+        let lock = Update::table(Transaction::tablename())
+            .set("last_modified", Utc::now())
+            .so_that("gid".equals(gid).and("state".equals(State::Prepared)));
+        if db.execute(lock.into()).await? == 0 {
+            db.rollback().await?;
+            let q = Select::from_table(Transaction::tablename()).so_that("gid".equals(gid));
+            let state = Transaction::from_first(conn.select(q).await?)?
+                .map(|tx| tx.state().tag().to_string())
+                .unwrap_or_default();
+            return Err(errors::Error::BranchRegisterDenied(gid, state));
+        }
+        insert_branches(&db, branches).await?;
+        db.commit().await?;
+        Ok(())
+    }
+
+    async fn update_branch(&self, branch: &TransactionBranch) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        update_branch(&*db, branch).await
+    }
+
+    async fn advance_branch(&self, branch: &TransactionBranch, next: &[TransactionBranch]) -> Result<(), errors::Error> {
+        let conn = self.check_out().await?;
+        let db = conn.start_transaction().await?;
+        insert_branches(&db, next).await?;
+        update_branch(&db, branch).await?;
+        db.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_audit(&self, audit: &Audit) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        let insertion = Insert::multi_into(Audit::tablename(), Audit::columns()).values(audit.values());
+        db.insert(insertion.build()).await?;
+        Ok(())
+    }
+
+    async fn audits(&self, gid: Gid) -> Result<Vec<Audit>, errors::Error> {
+        let db = self.check_out().await?;
+        let q = Select::from_table(Audit::tablename())
+            .so_that("gid".equals(gid))
+            .order_by("id".ascend());
+        Audit::from_rows(db.select(q).await?)
+    }
+
+    async fn insert_event(&self, event: &Event) -> Result<(), errors::Error> {
+        let db = self.check_out().await?;
+        let insertion = Insert::multi_into(Event::tablename(), Event::columns()).values(event.values());
+        db.insert(insertion.build()).await?;
+        Ok(())
+    }
+
+    async fn events(&self, gid: Gid) -> Result<Vec<Event>, errors::Error> {
+        let db = self.check_out().await?;
+        let q = Select::from_table(Event::tablename())
+            .so_that("gid".equals(gid))
+            .order_by("id".ascend());
+        Event::from_rows(db.select(q).await?)
+    }
+}
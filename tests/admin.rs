@@ -0,0 +1,119 @@
+//! Admin routes against the in-memory store.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+
+use luwu::config::{Config, CONFIG};
+use luwu::database;
+use luwu::models::transaction::{Gid, State, TCCBranchCreation, Transaction, TransactionCreation};
+use luwu::processors::{ProcessorType, Saga, SagaStep, TCC};
+use luwu::store::{MemoryStore, Store};
+
+use common::{Rm, OK};
+
+async fn client() -> (Client, Arc<MemoryStore>) {
+    let mut config = Config::default();
+    config.admin_tokens.insert("alice".to_string(), "secret".to_string());
+    CONFIG.set(config).unwrap();
+    let store = Arc::new(MemoryStore::new());
+    database::set_store(store.clone());
+    let rocket = rocket::build().mount("/admin", luwu::admin::routes());
+    (Client::tracked(rocket).await.unwrap(), store)
+}
+
+async fn put<'c>(client: &'c Client, uri: String, token: &str, reason: &str) -> LocalResponse<'c> {
+    client
+        .put(uri)
+        .header(ContentType::JSON)
+        .header(Header::new("x-api-version", "1.0.0"))
+        .header(Header::new("authorization", format!("Bearer {}", token)))
+        .body(serde_json::json!({ "reason": reason }).to_string())
+        .dispatch()
+        .await
+}
+
+#[rocket::async_test]
+async fn it_moves_transactions_and_audits_the_operator() {
+    let (client, db) = client().await;
+    let mut tx = Transaction::from(TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new()));
+    tx.save(db.as_ref()).await.unwrap();
+    let gid = tx.gid();
+    let branches = TCCBranchCreation::new(
+        "{}".to_string(),
+        "http://rm/try".to_string(),
+        "http://rm/confirm".to_string(),
+        "http://rm/cancel".to_string(),
+    )
+    .into_branches(gid, Gid::new_v4());
+    tx.register_branches(db.as_ref(), &branches).await.unwrap();
+
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", gid), "wrong", "fixed").await;
+    assert_eq!(resp.status(), Status::Unauthorized);
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", gid), "secret", " ").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+    // prepared 的事务不能重试
+    let resp = put(&client, format!("/admin/transactions/{}/retry", gid), "secret", "stuck").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", gid), "secret", "fixed by hand").await;
+    assert_eq!(resp.status(), Status::Ok);
+    let loaded = Transaction::load(gid, db.as_ref()).await.unwrap();
+    assert_eq!(loaded.state(), State::Succeed);
+    assert_eq!(loaded.reason(), Some("marked succeed by alice: fixed by hand"));
+
+    let audits = db.audits(gid).await.unwrap();
+    assert_eq!(audits.len(), 1);
+    assert_eq!(audits[0].operator(), "alice");
+    assert_eq!(audits[0].action(), "mark-succeed");
+    assert_eq!(audits[0].reason(), "fixed by hand");
+    // 已经完成的事务不能再标记
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", gid), "secret", "again").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+
+    // 已提交的 tcc 不能强制回滚
+    let mut submitted = Transaction::from(TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new()));
+    submitted.submitted();
+    submitted.save(db.as_ref()).await.unwrap();
+    let resp = put(&client, format!("/admin/transactions/{}/force-abort", submitted.gid()), "secret", "stuck").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+    assert_eq!(Transaction::load(submitted.gid(), db.as_ref()).await.unwrap().state(), State::Submitted);
+
+    // 正在处理的事务不能标记成功，也不能重试
+    assert!(submitted.acquire_lease(db.as_ref()).await.unwrap());
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", submitted.gid()), "secret", "fixed").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+    let resp = put(&client, format!("/admin/transactions/{}/retry", submitted.gid()), "secret", "stuck").await;
+    assert_eq!(resp.status(), Status::InternalServerError);
+    assert!(db.audits(submitted.gid()).await.unwrap().is_empty());
+    submitted.release_lease(db.as_ref()).await.unwrap();
+    let resp = put(&client, format!("/admin/transactions/{}/mark-succeed", submitted.gid()), "secret", "fixed").await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    // 强制回滚 saga 时，结果不确定的步骤也要补偿
+    let rm = Rm::start(&[("/c0", 502, "<html>Bad Gateway</html>"), ("/r0", 200, OK)]).await;
+    let steps = (0..2)
+        .map(|i| SagaStep::new(rm.url(&format!("/c{}", i)), rm.url(&format!("/r{}", i)), "{}".to_string()))
+        .collect();
+    let mut saga = Transaction::from(TransactionCreation::new(ProcessorType::Saga(Saga::new(steps, false)), String::new(), String::new()));
+    saga.submitted();
+    saga.save(db.as_ref()).await.unwrap();
+    assert!(saga.process(db.as_ref()).await.is_err());
+    let resp = put(&client, format!("/admin/transactions/{}/force-abort", saga.gid()), "secret", "stuck on c0").await;
+    assert_eq!(resp.status(), Status::Ok);
+    // 回滚在后台进行
+    for _ in 0..50 {
+        if Transaction::load(saga.gid(), db.as_ref()).await.unwrap().state() == State::Failed {
+            break;
+        }
+        rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let loaded = Transaction::load(saga.gid(), db.as_ref()).await.unwrap();
+    assert_eq!(loaded.state(), State::Failed);
+    assert_eq!(loaded.reason(), Some("aborted by alice: stuck on c0"));
+    assert_eq!(rm.calls(), ["/c0", "/r0"]);
+}
@@ -0,0 +1,116 @@
+//! A local RM answering the calls of luwu with canned responses.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::tokio;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::net::{TcpListener, TcpStream};
+
+pub const OK: &str = r#"{"message":"Ok"}"#;
+pub const FAILURE: &str = r#"{"message":"FAILURE","code":5020}"#;
+/// 不返回任何内容，直到调用方超时
+pub const HANG: u16 = 0;
+
+type Replies = Arc<Mutex<HashMap<String, Vec<(u16, String)>>>>;
+
+pub struct Rm {
+    addr: SocketAddr,
+    calls: Arc<Mutex<Vec<String>>>,
+}
+
+impl Rm {
+    /// Answer each path with `(status, body)`, a path listed more than once is
+    /// answered with its replies in turn, repeating the last one.
+    pub async fn start(routes: &[(&str, u16, &str)]) -> Rm {
+        let mut replies: HashMap<String, Vec<(u16, String)>> = HashMap::new();
+        for (path, status, body) in routes {
+            replies
+                .entry(path.to_string())
+                .or_default()
+                .push((*status, body.to_string()));
+        }
+        let replies = Arc::new(Mutex::new(replies));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, replies.clone(), recorded.clone()));
+            }
+        });
+        Rm { addr, calls }
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Paths called so far, in the order they were called.
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn called(&self, path: &str) -> usize {
+        self.calls().iter().filter(|call| call.as_str() == path).count()
+    }
+}
+
+async fn serve(mut stream: TcpStream, replies: Replies, calls: Arc<Mutex<Vec<String>>>) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    // 读完请求头，以及 Content-Length 指定长度的请求体
+    let head_len = loop {
+        let n = match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+    let head = String::from_utf8_lossy(&buf[..head_len]).to_string();
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < head_len + length {
+        let n = match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let target = head.split_whitespace().nth(1).unwrap_or("/");
+    let path = target.split('?').next().unwrap_or("/").to_string();
+    calls.lock().unwrap().push(path.clone());
+    let reply = {
+        let mut replies = replies.lock().unwrap();
+        match replies.get_mut(&path) {
+            Some(list) if list.len() > 1 => Some(list.remove(0)),
+            Some(list) => list.first().cloned(),
+            None => None,
+        }
+    };
+    let (status, body) = reply.unwrap_or((404, "not found".to_string()));
+    if status == HANG {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        return;
+    }
+    let resp = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(resp.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
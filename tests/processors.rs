@@ -0,0 +1,432 @@
+//! Processors against the in-memory store and a local RM.
+
+mod common;
+
+use luwu::config::{Config, CONFIG};
+use luwu::errors::Error;
+use luwu::models::transaction::{
+    Gid, State, TCCBranchCreation, Transaction, TransactionBranch, TransactionCreation, XaBranchCreation,
+};
+use luwu::processors::{Message, MessageStep, Outcome, ProcessorType, Saga, SagaStep, Xa, TCC};
+use luwu::models::RequestOptions;
+use luwu::store::{MemoryStore, Store};
+
+use common::{Rm, FAILURE, HANG, OK};
+
+fn store() -> MemoryStore {
+    let mut config = Config::default();
+    // 回查一秒仍不确定的消息即放弃
+    config.max_back_check_age = 1;
+    let _ = CONFIG.set(config);
+    MemoryStore::new()
+}
+
+async fn branch(db: &dyn Store, tx: &Transaction, r#type: &str) -> TransactionBranch {
+    db.branches(tx.gid())
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|branch| branch.r#type() == r#type)
+        .unwrap()
+}
+
+/// A prepared tcc transaction whose branches are served by `rm`.
+async fn tcc(db: &dyn Store, rm: &Rm) -> Transaction {
+    tcc_with(db, rm, TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new())).await
+}
+
+async fn tcc_with(db: &dyn Store, rm: &Rm, creation: TransactionCreation) -> Transaction {
+    let mut tx = Transaction::from(creation);
+    tx.save(db).await.unwrap();
+    let branches = TCCBranchCreation::new("{}".to_string(), rm.url("/try"), rm.url("/confirm"), rm.url("/cancel"))
+        .into_branches(tx.gid(), Gid::new_v4());
+    tx.register_branches(db, &branches).await.unwrap();
+    tx
+}
+
+/// A submitted saga of `steps`.
+async fn saga(db: &dyn Store, steps: Vec<SagaStep>, concurrent: bool) -> Transaction {
+    let saga = Saga::new(steps, concurrent);
+    let mut tx = Transaction::from(TransactionCreation::new(ProcessorType::Saga(saga), String::new(), String::new()));
+    tx.submitted();
+    tx.save(db).await.unwrap();
+    tx
+}
+
+/// A submitted state machine, `pay` chooses `ship` or `refund` by `/data/status`.
+async fn machine(db: &dyn Store, rm: &Rm) -> Transaction {
+    let machine = serde_json::json!({
+        "type": "state_machine",
+        "initial": "reserve",
+        "states": {
+            "reserve": { "action": rm.url("/reserve"), "compensation": rm.url("/release"), "next": "pay" },
+            "pay": {
+                "action": rm.url("/pay"),
+                "compensation": rm.url("/refund"),
+                "transitions": [{ "field": "/data/status", "equals": "paid", "next": "ship" }],
+                "next": "failed"
+            },
+            "ship": { "action": rm.url("/ship"), "next": "done" },
+            "done": { "end": "succeed" },
+            "failed": { "end": "failed" }
+        }
+    });
+    let r#type: ProcessorType = serde_json::from_value(machine).unwrap();
+    r#type.validate().unwrap();
+    let mut tx = Transaction::from(TransactionCreation::new(r#type, String::new(), String::new()));
+    tx.submitted();
+    tx.save(db).await.unwrap();
+    tx
+}
+
+/// Step `i` of a saga, which calls `/c{i}` to commit and `/r{i}` to compensate.
+fn step(rm: &Rm, i: usize) -> SagaStep {
+    SagaStep::new(rm.url(&format!("/c{}", i)), rm.url(&format!("/r{}", i)), "{}".to_string())
+}
+
+#[test]
+fn it_reads_outcomes_from_bodies() {
+    let cases = [
+        (r#"{"message":"Ok"}"#, Outcome::Succeed),
+        (r#"{"message":"Ok","data":{"id":1}}"#, Outcome::Succeed),
+        (" SUCCESS\n", Outcome::Succeed),
+        (r#"{"message":"not enough balance","code":5020}"#, Outcome::Failed),
+        (r#"{"message":"FAILURE"}"#, Outcome::Failed),
+        ("FAILURE", Outcome::Failed),
+        // 带了其他错误码的 Ok 也不算成功
+        (r#"{"message":"Ok","code":5999}"#, Outcome::Retry),
+        (r#"{"message":"busy","code":5010}"#, Outcome::Retry),
+        (r#"{"code":5020}"#, Outcome::Retry),
+        ("<html>Bad Gateway</html>", Outcome::Retry),
+        ("ok", Outcome::Retry),
+        ("", Outcome::Retry),
+    ];
+    for (body, outcome) in cases {
+        assert_eq!(Outcome::from_body(body), outcome, "{}", body);
+    }
+}
+
+#[test]
+fn it_reads_outcomes_from_statuses_and_bodies() {
+    let cases = [
+        (200, OK, Outcome::Succeed),
+        (201, "SUCCESS", Outcome::Succeed),
+        (500, OK, Outcome::Retry),
+        (404, "SUCCESS", Outcome::Retry),
+        (302, OK, Outcome::Retry),
+        // 明确的失败与状态码无关
+        (200, FAILURE, Outcome::Failed),
+        (500, FAILURE, Outcome::Failed),
+        (409, "FAILURE", Outcome::Failed),
+        (502, "<html>Bad Gateway</html>", Outcome::Retry),
+        (200, "not json", Outcome::Retry),
+    ];
+    for (status, body, outcome) in cases {
+        assert_eq!(Outcome::from_response(status, body), outcome, "{} {}", status, body);
+    }
+}
+
+#[rocket::async_test]
+async fn it_applies_replies_to_branches() {
+    let db = store();
+    // confirm 不允许失败，除了成功都要重试
+    let cases = [
+        (200, OK, true),
+        (200, "SUCCESS", true),
+        (500, OK, false),
+        (502, "<html>Bad Gateway</html>", false),
+        (200, FAILURE, false),
+        (200, "not json", false),
+    ];
+    for (status, body, succeed) in cases {
+        let rm = Rm::start(&[("/confirm", status, body)]).await;
+        let mut tx = tcc(&db, &rm).await;
+        tx.submitted();
+        tx.save(&db).await.unwrap();
+
+        let processed = tx.process(&db).await;
+        let confirm = branch(&db, &tx, "confirm").await;
+        assert_eq!(confirm.attempts(), 1, "{} {}", status, body);
+        let loaded = Transaction::load(tx.gid(), &db).await.unwrap();
+        if succeed {
+            assert!(processed.is_ok(), "{} {}", status, body);
+            assert_eq!(confirm.state(), State::Succeed);
+            assert_eq!(loaded.state(), State::Succeed);
+        } else {
+            assert!(matches!(processed, Err(Error::UnexpectedResponse(..))), "{} {}", status, body);
+            assert_eq!(confirm.state(), State::Prepared);
+            assert_eq!(loaded.state(), State::Submitted);
+            // 不确定的结果会推迟重试
+            assert!(loaded.delay() > 0);
+        }
+        assert_eq!(rm.calls(), ["/confirm"]);
+        let events = db.events(tx.gid()).await.unwrap();
+        let call = events.iter().find(|event| event.kind() == "call").unwrap();
+        assert_eq!(call.status(), Some(status as i64));
+        assert_eq!(call.detail(), Some(body));
+        assert_eq!(events.iter().any(|event| event.kind() == "retry"), !succeed);
+    }
+}
+
+#[rocket::async_test]
+async fn it_compensates_an_uncertain_saga_step_once_aborted() {
+    let db = store();
+    let rm = Rm::start(&[("/c0", 200, OK), ("/c1", 502, "<html>Bad Gateway</html>"), ("/r0", 200, OK), ("/r1", 200, OK)]).await;
+    let mut tx = saga(&db, vec![step(&rm, 0), step(&rm, 1), step(&rm, 2)], false).await;
+    assert!(tx.process(&db).await.is_err());
+    assert_eq!(rm.calls(), ["/c0", "/c1"]);
+
+    // /c1 可能已经在 RM 生效，放弃后也要补偿
+    tx.abort(&db, "given up").await.unwrap();
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/c0", "/c1", "/r1", "/r0"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+}
+
+#[rocket::async_test]
+async fn it_compensates_siblings_which_timed_out_with_a_failed_step() {
+    let db = store();
+    let rm = Rm::start(&[("/c0", 200, FAILURE), ("/c1", HANG, ""), ("/r0", 200, OK), ("/r1", 200, OK)]).await;
+    let steps = vec![step(&rm, 0), step(&rm, 1).with_options(RequestOptions::new().with_timeout(1))];
+    let mut tx = saga(&db, steps, true).await;
+    // 同一批中 /c0 失败、/c1 超时，一次处理即回滚完成
+    tx.process(&db).await.unwrap();
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+    assert_eq!(rm.called("/r0"), 1);
+    assert_eq!(rm.called("/r1"), 1);
+    let branches = tx.branches(&db).await.unwrap();
+    let committing: Vec<_> = branches.iter().filter(|branch| branch.r#type() == "on_committing").collect();
+    assert!(committing.iter().any(|branch| branch.state() == State::Failed));
+    assert!(committing.iter().any(|branch| branch.state() == State::Prepared && branch.attempts() == 1));
+}
+
+#[rocket::async_test]
+async fn it_compensates_an_uncertain_action_of_a_state_machine() {
+    let db = store();
+    let rm = Rm::start(&[
+        ("/reserve", 200, OK),
+        ("/pay", 502, "<html>Bad Gateway</html>"),
+        ("/release", 200, OK),
+        ("/refund", 200, OK),
+    ])
+    .await;
+    let mut tx = machine(&db, &rm).await;
+    assert!(tx.process(&db).await.is_err());
+    assert_eq!(rm.calls(), ["/reserve", "/pay"]);
+
+    // 成功的动作只写一次状态
+    let events = db.events(tx.gid()).await.unwrap();
+    let succeed: Vec<_> = events
+        .iter()
+        .filter(|event| event.branch_type() == Some("action") && event.state() == Some(State::Succeed))
+        .collect();
+    assert_eq!(succeed.len(), 1);
+    let actions: Vec<_> = tx
+        .branches(&db)
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|branch| branch.r#type() == "action")
+        .map(|branch| (branch.state(), branch.attempts()))
+        .collect();
+    assert_eq!(actions, [(State::Succeed, 1), (State::Prepared, 1)]);
+
+    tx.abort(&db, "given up").await.unwrap();
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/reserve", "/pay", "/refund", "/release"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+}
+
+#[rocket::async_test]
+async fn it_aborts_prepared_transactions_only_after_the_deadline() {
+    let db = store();
+    let rm = Rm::start(&[("/cancel", 200, OK)]).await;
+    // 没有设置 timeout_to_fail 时，使用配置中默认的 60 秒
+    let mut pending = tcc(&db, &rm).await;
+    assert!(pending.deadline().is_some());
+    pending.process(&db).await.unwrap();
+    assert_eq!(Transaction::load(pending.gid(), &db).await.unwrap().state(), State::Prepared);
+    assert!(rm.calls().is_empty());
+
+    let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new()).with_timeout_to_fail(0);
+    let mut expired = tcc_with(&db, &rm, creation).await;
+    expired.process(&db).await.unwrap();
+    let loaded = Transaction::load(expired.gid(), &db).await.unwrap();
+    assert_eq!(loaded.state(), State::Failed);
+    assert_eq!(loaded.reason(), Some("timeout"));
+    assert_eq!(rm.calls(), ["/cancel"]);
+}
+
+#[rocket::async_test]
+async fn it_commits_saga_steps_in_order() {
+    let db = store();
+    let rm = Rm::start(&[("/c0", 200, OK), ("/c1", 200, OK), ("/c2", 200, OK)]).await;
+    let mut tx = saga(&db, vec![step(&rm, 0), step(&rm, 1), step(&rm, 2)], false).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/c0", "/c1", "/c2"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Succeed);
+}
+
+#[rocket::async_test]
+async fn it_commits_concurrent_saga_steps_in_waves() {
+    let db = store();
+    let rm = Rm::start(&[("/c0", 200, OK), ("/c1", 200, OK), ("/c2", 200, OK), ("/c3", 200, OK)]).await;
+    // 0 和 1 同时执行，2 等待两者成功，3 只依赖 0
+    let steps = vec![
+        step(&rm, 0),
+        step(&rm, 1),
+        step(&rm, 2).with_depends_on(vec![0, 1]),
+        step(&rm, 3).with_depends_on(vec![0]),
+    ];
+    let mut tx = saga(&db, steps, true).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Succeed);
+    let calls = rm.calls();
+    let mut first = calls[..2].to_vec();
+    first.sort();
+    assert_eq!(first, ["/c0", "/c1"]);
+    let mut second = calls[2..].to_vec();
+    second.sort();
+    assert_eq!(second, ["/c2", "/c3"]);
+}
+
+#[rocket::async_test]
+async fn it_compensates_saga_steps_in_reverse_order() {
+    let db = store();
+    let rm = Rm::start(&[
+        ("/c0", 200, OK),
+        ("/c1", 200, OK),
+        ("/c2", 200, FAILURE),
+        ("/r0", 200, OK),
+        ("/r1", 200, OK),
+        ("/r2", 200, OK),
+    ])
+    .await;
+    let mut tx = saga(&db, vec![step(&rm, 0), step(&rm, 1), step(&rm, 2), step(&rm, 3)], false).await;
+    tx.process(&db).await.unwrap();
+    // 失败的步骤也可能部分生效，同样补偿；没有执行的步骤不补偿
+    assert_eq!(rm.calls(), ["/c0", "/c1", "/c2", "/r2", "/r1", "/r0"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+}
+
+#[rocket::async_test]
+async fn it_moves_a_state_machine_by_the_responses_of_actions() {
+    let db = store();
+    let paid = r#"{"message":"Ok","data":{"status":"paid"}}"#;
+    let rm = Rm::start(&[("/reserve", 200, OK), ("/pay", 200, paid), ("/ship", 200, OK)]).await;
+    let mut tx = machine(&db, &rm).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/reserve", "/pay", "/ship"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Succeed);
+
+    // 没有匹配的转移时进入默认的失败状态，补偿执行过的动作
+    let declined = r#"{"message":"Ok","data":{"status":"declined"}}"#;
+    let rm = Rm::start(&[("/reserve", 200, OK), ("/pay", 200, declined), ("/refund", 200, OK), ("/release", 200, OK)]).await;
+    let mut tx = machine(&db, &rm).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/reserve", "/pay", "/refund", "/release"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+}
+
+#[rocket::async_test]
+async fn it_commits_or_rolls_back_xa_branches() {
+    let db = store();
+    for submitted in [true, false] {
+        let rm = Rm::start(&[("/commit", 200, OK), ("/rollback", 200, OK)]).await;
+        let mut tx = Transaction::from(TransactionCreation::new(ProcessorType::Xa(Xa {}), String::new(), String::new()));
+        tx.save(&db).await.unwrap();
+        for _ in 0..2 {
+            let branches = XaBranchCreation::new(Gid::new_v4(), rm.url("/commit"), rm.url("/rollback"), "{}".to_string())
+                .into_branches(tx.gid());
+            tx.register_branches(&db, &branches).await.unwrap();
+        }
+        if submitted {
+            tx.submitted();
+            tx.save(&db).await.unwrap();
+        } else {
+            tx.abort(&db, "aborted by application").await.unwrap();
+        }
+        tx.process(&db).await.unwrap();
+        let (called, state) = if submitted {
+            ("/commit", State::Succeed)
+        } else {
+            ("/rollback", State::Failed)
+        };
+        assert_eq!(rm.calls(), [called, called]);
+        assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), state);
+        let branches = tx.branches(&db).await.unwrap();
+        assert!(branches
+            .iter()
+            .filter(|branch| branch.url().ends_with(called))
+            .all(|branch| branch.state() == State::Succeed));
+    }
+}
+
+#[rocket::async_test]
+async fn it_confirms_or_cancels_tcc_branches() {
+    let db = store();
+    for submitted in [true, false] {
+        let rm = Rm::start(&[("/confirm", 200, OK), ("/cancel", 200, OK)]).await;
+        let mut tx = tcc(&db, &rm).await;
+        if submitted {
+            tx.submitted();
+            tx.save(&db).await.unwrap();
+        } else {
+            tx.abort(&db, "aborted by application").await.unwrap();
+        }
+        tx.process(&db).await.unwrap();
+        // try 由 AP 调用，TM 不会调用
+        let (called, state) = if submitted {
+            ("/confirm", State::Succeed)
+        } else {
+            ("/cancel", State::Failed)
+        };
+        assert_eq!(rm.calls(), [called]);
+        assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), state);
+    }
+}
+
+/// A prepared message which checks back with `/query` and notifies `/notify`.
+async fn message(db: &dyn Store, rm: &Rm) -> Transaction {
+    let message = Message::new(vec![MessageStep::new("{}".to_string(), rm.url("/notify"))], rm.url("/query"));
+    let mut tx = Transaction::from(TransactionCreation::new(ProcessorType::Message(message), String::new(), rm.url("/query")));
+    tx.save(db).await.unwrap();
+    tx
+}
+
+#[rocket::async_test]
+async fn it_checks_back_prepared_messages() {
+    let db = store();
+    let rm = Rm::start(&[("/query", 200, OK), ("/notify", 200, OK)]).await;
+    let mut tx = message(&db, &rm).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/query", "/notify"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Succeed);
+
+    let rm = Rm::start(&[("/query", 200, FAILURE)]).await;
+    let mut tx = message(&db, &rm).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(rm.calls(), ["/query"]);
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+}
+
+#[rocket::async_test]
+async fn it_gives_up_a_message_uncertain_for_too_long() {
+    let db = store();
+    let rm = Rm::start(&[("/query", 502, "<html>Bad Gateway</html>")]).await;
+    let mut tx = message(&db, &rm).await;
+    tx.process(&db).await.unwrap();
+    let first = Transaction::load(tx.gid(), &db).await.unwrap();
+    assert_eq!(first.state(), State::Prepared);
+    // 结果不确定时回查的间隔加倍
+    tx.process(&db).await.unwrap();
+    let second = Transaction::load(tx.gid(), &db).await.unwrap();
+    assert_eq!(second.state(), State::Prepared);
+    assert!(second.delay() > first.delay());
+
+    // 超过 max_back_check_age 仍不确定，放弃这个消息
+    rocket::tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    tx.process(&db).await.unwrap();
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Failed);
+    assert_eq!(rm.calls(), ["/query", "/query", "/query"]);
+}
@@ -0,0 +1,74 @@
+//! Retry policy intervals and validation.
+
+use luwu::errors::Error;
+use luwu::models::transaction::TransactionCreation;
+use luwu::models::RetryPolicy;
+use luwu::processors::{ProcessorType, TCC};
+
+#[test]
+fn it_grows_intervals_up_to_max() {
+    let policy = RetryPolicy::new(2, 3.0, 50);
+    assert_eq!(policy.backoff(0), 2);
+    assert_eq!(policy.backoff(1), 2);
+    assert_eq!(policy.backoff(2), 6);
+    assert_eq!(policy.backoff(3), 18);
+    assert_eq!(policy.backoff(5), 50);
+    // 溢出时也不超过上限
+    assert_eq!(policy.backoff(1000), 50);
+
+    assert_eq!(policy.next(-1), 6);
+    assert_eq!(policy.next(6), 18);
+    assert_eq!(policy.next(40), 50);
+}
+
+#[test]
+fn it_exhausts_after_max_attempts() {
+    let unlimited = RetryPolicy::new(1, 2.0, 10);
+    assert!(!unlimited.exhausted(i64::MAX));
+    let policy = unlimited.with_max_attempts(3);
+    assert!(!policy.exhausted(2));
+    assert!(policy.exhausted(3));
+    assert!(policy.exhausted(4));
+}
+
+#[test]
+fn it_keeps_jittered_intervals_in_bounds() {
+    let policy = RetryPolicy::new(100, 2.0, 1000).with_jitter(0.5);
+    for _ in 0..1000 {
+        let delay = policy.backoff(2);
+        assert!((100..=300).contains(&delay), "{} out of bounds", delay);
+        let delay = policy.next(400);
+        assert!((400..=1200).contains(&delay), "{} out of bounds", delay);
+    }
+    // 抖动后也至少等一秒
+    let tiny = RetryPolicy::new(1, 1.0, 1).with_jitter(0.9);
+    for _ in 0..100 {
+        assert!(tiny.backoff(1) >= 1);
+    }
+}
+
+#[test]
+fn it_rejects_invalid_policies() {
+    assert!(RetryPolicy::new(1, 1.0, 10).with_jitter(0.0).validate().is_ok());
+    assert!(RetryPolicy::new(1, 2.0, 10).with_jitter(0.99).validate().is_ok());
+    assert!(RetryPolicy::new(5, 2.0, 5).with_max_attempts(1).validate().is_ok());
+    let invalid = vec![
+        RetryPolicy::new(0, 2.0, 10),
+        RetryPolicy::new(-1, 2.0, 10),
+        RetryPolicy::new(1, 0.5, 10),
+        RetryPolicy::new(1, f64::NAN, 10),
+        RetryPolicy::new(1, 2.0, 10).with_jitter(1.0),
+        RetryPolicy::new(1, 2.0, 10).with_jitter(-0.1),
+        RetryPolicy::new(1, 2.0, 0),
+        RetryPolicy::new(1, 2.0, -10),
+        RetryPolicy::new(5, 2.0, 4),
+        RetryPolicy::new(1, 2.0, 10).with_max_attempts(0),
+        RetryPolicy::new(1, 2.0, 10).with_max_attempts(-1),
+    ];
+    for policy in invalid {
+        assert!(matches!(policy.validate(), Err(Error::InvalidDefinition(..))), "{:?}", policy);
+        let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new())
+            .with_retry_policy(policy);
+        assert!(matches!(creation.validate(), Err(Error::InvalidDefinition(..))));
+    }
+}
@@ -0,0 +1,393 @@
+//! Models and routes against the in-memory store.
+
+use std::sync::Arc;
+
+use rocket::http::{Accept, ContentType, Header, Status};
+use rocket::local::asynchronous::Client;
+
+use luwu::config::{Config, CONFIG};
+use luwu::database;
+use luwu::errors::Error;
+use luwu::models::transaction::{Gid, State, TCCBranchCreation, Transaction, TransactionCreation};
+use luwu::models::RequestOptions;
+use luwu::processors::{ProcessorType, TCC};
+use luwu::store::{Cursor, MemoryStore, SortBy, Store, TransactionQuery};
+
+fn tcc() -> Transaction {
+    let _ = CONFIG.set(Config::default());
+    Transaction::from(TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new()))
+}
+
+fn branch() -> TCCBranchCreation {
+    TCCBranchCreation::new(
+        "{}".to_string(),
+        "http://rm/try".to_string(),
+        "http://rm/confirm".to_string(),
+        "http://rm/cancel".to_string(),
+    )
+}
+
+#[rocket::async_test]
+async fn it_registers_branches_only_while_prepared() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    let gid = tx.gid();
+    tx.register_branches(&db, &branch().into_branches(gid, Gid::new_v4()))
+        .await
+        .unwrap();
+    assert_eq!(tx.branches(&db).await.unwrap().len(), 3);
+
+    tx.update_state(&db, State::Aborting).await.unwrap();
+    let denied = tx
+        .register_branches(&db, &branch().into_branches(gid, Gid::new_v4()))
+        .await;
+    assert!(matches!(denied, Err(Error::BranchRegisterDenied(..))));
+    let loaded = Transaction::load(gid, &db).await.unwrap();
+    assert_eq!(loaded.state(), State::Aborting);
+    assert_eq!(loaded.branches(&db).await.unwrap().len(), 3);
+}
+
+#[rocket::async_test]
+async fn it_resets_attempts_of_pending_branches_on_retry() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    tx.register_branches(&db, &branch().into_branches(tx.gid(), Gid::new_v4()))
+        .await
+        .unwrap();
+    let mut branches = tx.branches(&db).await.unwrap();
+    branches[0].attempt(&db).await.unwrap();
+    branches[1].attempt(&db).await.unwrap();
+    branches[1].update_state(&db, State::Succeed).await.unwrap();
+
+    tx.retry(&db).await.unwrap();
+    let branches = tx.branches(&db).await.unwrap();
+    assert_eq!(branches[0].attempts(), 0);
+    // 已完成的分支保持不变
+    assert_eq!(branches[1].attempts(), 1);
+    assert_eq!(tx.delay(), 0);
+}
+
+#[rocket::async_test]
+async fn it_appends_state_transitions_to_events() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    tx.register_branches(&db, &branch().into_branches(tx.gid(), Gid::new_v4()))
+        .await
+        .unwrap();
+    let mut branches = tx.branches(&db).await.unwrap();
+    branches[0].update_state(&db, State::Succeed).await.unwrap();
+    tx.abort(&db, "aborted by application").await.unwrap();
+
+    let events = db.events(tx.gid()).await.unwrap();
+    let states: Vec<_> = events.iter().map(|event| (event.kind(), event.state(), event.branch_type())).collect();
+    assert_eq!(
+        states,
+        [
+            ("state", Some(State::Prepared), None),
+            ("state", Some(State::Succeed), Some("cancel")),
+            ("state", Some(State::Aborting), None),
+        ]
+    );
+    assert_eq!(events[2].detail(), Some("aborted by application"));
+}
+
+#[rocket::async_test]
+async fn it_does_not_overwrite_a_state_changed_concurrently() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    let mut stale = Transaction::load(tx.gid(), &db).await.unwrap();
+    tx.abort(&db, "timeout").await.unwrap();
+
+    let changed = stale.update_state(&db, State::Submitted).await;
+    assert!(matches!(changed, Err(Error::StateChanged(..))));
+    stale.submitted();
+    let submitted = stale.save(&db).await;
+    assert!(matches!(submitted, Err(Error::CannotSubmitTransaction(..))));
+    assert_eq!(Transaction::load(tx.gid(), &db).await.unwrap().state(), State::Aborting);
+}
+
+#[rocket::async_test]
+async fn it_claims_due_transactions_once() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+
+    // 立即到期
+    tx.touch(&db, -1).await.unwrap();
+    let other = Gid::new_v4();
+    let lease = chrono::Local::now() + chrono::Duration::seconds(30);
+    let claimed = db.claim_due(other, lease).await.unwrap().unwrap();
+    assert_eq!(claimed.gid(), tx.gid());
+    assert!(db.claim_due(Gid::new_v4(), lease).await.unwrap().is_none());
+    assert!(!tx.acquire_lease(&db).await.unwrap());
+
+    db.release_lease(tx.gid(), other).await.unwrap();
+    assert!(tx.acquire_lease(&db).await.unwrap());
+}
+
+#[rocket::async_test]
+async fn it_does_not_process_a_leased_transaction_twice() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    tx.touch(&db, -1).await.unwrap();
+    assert!(tx.acquire_lease(&db).await.unwrap());
+
+    // 同一实例内，cron 和路由也不能同时处理
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+    let mut again = Transaction::load(tx.gid(), &db).await.unwrap();
+    assert!(!again.acquire_lease(&db).await.unwrap());
+
+    // 租约被别人拿走后，续约失败并停止处理
+    db.release_lease(tx.gid(), tx.owner().unwrap()).await.unwrap();
+    assert!(again.acquire_lease(&db).await.unwrap());
+    assert!(matches!(tx.touch(&db, 10).await, Err(Error::LeaseLost(..))));
+    again.release_lease(&db).await.unwrap();
+}
+
+#[rocket::async_test]
+async fn it_hands_a_transaction_over_only_after_the_lease_expired() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    tx.touch(&db, -1).await.unwrap();
+
+    // 第一个实例认领后，第二个实例在租约有效期内既认领不到也拿不到租约
+    let mut first = Transaction::lock_expired(&db).await.unwrap().unwrap();
+    let mut second = Transaction::load(tx.gid(), &db).await.unwrap();
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+    assert!(!second.acquire_lease(&db).await.unwrap());
+    let lease = chrono::Local::now() + chrono::Duration::seconds(30);
+    assert!(db.claim_due(Gid::new_v4(), lease).await.unwrap().is_none());
+    // 续约后仍由第一个实例持有，到期了也不会被认领
+    first.touch(&db, -1).await.unwrap();
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+
+    // 第一个实例卡住直到租约过期，第二个实例接管
+    let expired = chrono::Local::now() - chrono::Duration::seconds(1);
+    assert!(db.acquire_lease(tx.gid(), first.owner().unwrap(), expired).await.unwrap());
+    let mut second = Transaction::lock_expired(&db).await.unwrap().unwrap();
+    assert_eq!(second.gid(), tx.gid());
+    assert!(matches!(first.touch(&db, 10).await, Err(Error::LeaseLost(..))));
+    // 失去租约后释放也不会影响新的持有者
+    first.release_lease(&db).await.unwrap();
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+    second.touch(&db, 10).await.unwrap();
+    second.release_lease(&db).await.unwrap();
+}
+
+#[rocket::async_test]
+async fn it_grows_the_delay_only_after_a_failed_pass() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    let initial = tx.retry_policy().initial_interval();
+
+    tx.touch(&db, -1).await.unwrap();
+    let mut claimed = Transaction::lock_expired(&db).await.unwrap().unwrap();
+    assert_eq!(claimed.delay(), -1);
+    claimed.reschedule(&db, false).await.unwrap();
+    assert_eq!(claimed.delay(), initial);
+    claimed.release_lease(&db).await.unwrap();
+    // 重新安排后不会被立即再次认领
+    assert!(Transaction::lock_expired(&db).await.unwrap().is_none());
+
+    claimed.touch(&db, -1).await.unwrap();
+    let mut claimed = Transaction::lock_expired(&db).await.unwrap().unwrap();
+    claimed.reschedule(&db, true).await.unwrap();
+    assert_eq!(claimed.delay(), claimed.retry_policy().next(initial));
+    // 本轮已经安排过的不再改动
+    claimed.reschedule(&db, true).await.unwrap();
+    assert_eq!(claimed.delay(), claimed.retry_policy().next(initial));
+    claimed.release_lease(&db).await.unwrap();
+}
+
+#[rocket::async_test]
+async fn it_lists_transactions_page_by_page() {
+    let _ = CONFIG.set(Config::default());
+    let db = MemoryStore::new();
+    let mut gids = Vec::new();
+    for n in 0..5 {
+        let mut tx = Transaction::from(TransactionCreation::new(
+            ProcessorType::TCC(TCC {}),
+            format!("order-{}", n),
+            String::new(),
+        ));
+        tx.save(&db).await.unwrap();
+        gids.push(tx.gid());
+    }
+    let mut aborted = Transaction::load(gids[1], &db).await.unwrap();
+    aborted.update_state(&db, State::Aborting).await.unwrap();
+
+    let query = TransactionQuery::new(2).with_sort(SortBy::CreatedAt, true);
+    let first = db.list(&query).await.unwrap();
+    assert_eq!(first.iter().map(|tx| tx.gid()).collect::<Vec<_>>(), [gids[4], gids[3]]);
+    let cursor = Cursor::parse(&query.cursor(&first[1]).to_string()).unwrap();
+    let second = db.list(&query.clone().with_after(cursor)).await.unwrap();
+    assert_eq!(second.iter().map(|tx| tx.gid()).collect::<Vec<_>>(), [gids[2], gids[1]]);
+
+    let aborting = db
+        .list(&TransactionQuery::new(10).with_state(State::Aborting))
+        .await
+        .unwrap();
+    assert_eq!(aborting.len(), 1);
+    assert_eq!(aborting[0].gid(), gids[1]);
+    let found = db
+        .list(&TransactionQuery::new(10).with_payload("der-3".to_string()).with_processor("tcc".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].gid(), gids[3]);
+    let wildcard = db.list(&TransactionQuery::new(10).with_payload("order_".to_string())).await.unwrap();
+    assert!(wildcard.is_empty());
+    let none = db
+        .list(&TransactionQuery::new(10).with_processor("saga".to_string()))
+        .await
+        .unwrap();
+    assert!(none.is_empty());
+}
+
+#[rocket::async_test]
+async fn it_rejects_request_options_which_can_not_be_sent() {
+    let _ = CONFIG.set(Config::default());
+    database::set_store(Arc::new(MemoryStore::new()));
+    let rocket = rocket::build().mount("/api", luwu::routes::routes());
+    let client = Client::tracked(rocket).await.unwrap();
+    let version = || Header::new("x-api-version", "1.0.0");
+
+    let unknown = RequestOptions::new().with_credential("nobody".to_string());
+    let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new())
+        .with_options(unknown);
+    let resp = client
+        .post("/api/transactions")
+        .header(ContentType::JSON)
+        .header(version())
+        .body(serde_json::to_string(&creation).unwrap())
+        .dispatch()
+        .await;
+    let body: serde_json::Value = serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["code"], 5040);
+
+    let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new());
+    let resp = client
+        .post("/api/transactions")
+        .header(ContentType::JSON)
+        .header(version())
+        .body(serde_json::to_string(&creation).unwrap())
+        .dispatch()
+        .await;
+    let gid = resp.into_string().await.unwrap();
+    let invalid = RequestOptions::new().with_header("x-tenant".to_string(), "t1\n".to_string());
+    let resp = client
+        .post(format!("/api/transactions/{}/branches/{}/tcc", gid, Gid::new_v4()))
+        .header(ContentType::JSON)
+        .header(version())
+        .body(serde_json::to_string(&branch().with_options(invalid)).unwrap())
+        .dispatch()
+        .await;
+    let body: serde_json::Value = serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["code"], 5040);
+
+    // 超时必须为正，且短于租约
+    assert!(RequestOptions::new().with_timeout(29).validate().is_ok());
+    for timeout in [0, -1, 30, 60] {
+        let options = RequestOptions::new().with_timeout(timeout);
+        assert!(matches!(options.validate(), Err(Error::InvalidDefinition(..))), "{}", timeout);
+    }
+}
+
+#[test]
+fn it_rejects_request_timeouts_not_shorter_than_the_lease() {
+    assert!(Config::default().validate().is_ok());
+    let mut config = Config::default();
+    config.request_timeout = config.lease;
+    assert!(matches!(config.validate(), Err(Error::InvalidConfig(..))));
+    config.request_timeout = 0;
+    assert!(matches!(config.validate(), Err(Error::InvalidConfig(..))));
+    let mut config = Config::default();
+    config.lease = 0;
+    assert!(matches!(config.validate(), Err(Error::InvalidConfig(..))));
+}
+
+#[rocket::async_test]
+async fn it_serves_routes_without_a_database() {
+    let _ = CONFIG.set(Config::default());
+    database::set_store(Arc::new(MemoryStore::new()));
+    let rocket = rocket::build().mount("/api", luwu::routes::routes());
+    let client = Client::tracked(rocket).await.unwrap();
+    let version = || Header::new("x-api-version", "1.0.0");
+
+    let creation = TransactionCreation::new(ProcessorType::TCC(TCC {}), String::new(), String::new());
+    let resp = client
+        .post("/api/transactions")
+        .header(ContentType::JSON)
+        .header(version())
+        .body(serde_json::to_string(&creation).unwrap())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let gid = resp.into_string().await.unwrap();
+
+    let resp = client
+        .post(format!("/api/transactions/{}/branches/{}/tcc", gid, Gid::new_v4()))
+        .header(ContentType::JSON)
+        .header(version())
+        .body(serde_json::to_string(&branch()).unwrap())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+
+    let resp = client
+        .get(format!("/api/transactions/{}", gid))
+        .header(Accept::JSON)
+        .header(version())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["branches"].as_array().map(Vec::len), Some(3));
+
+    let resp = client
+        .get("/api/transactions?state=prepared&type=tcc&limit=10")
+        .header(Accept::JSON)
+        .header(version())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+    assert_eq!(body["transactions"][0]["gid"], gid.as_str());
+    assert_eq!(body["transactions"][0]["branches"].as_array().map(Vec::len), Some(3));
+    assert!(body["next"].is_null());
+
+    let resp = client
+        .get(format!("/api/transactions/{}/events", gid))
+        .header(Accept::JSON)
+        .header(version())
+        .dispatch()
+        .await;
+    assert_eq!(resp.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+    assert_eq!(body[0]["kind"], "state");
+    assert_eq!(body[0]["state"], "Prepared");
+}
+
+#[rocket::async_test]
+async fn it_exports_metrics() {
+    let db = MemoryStore::new();
+    let mut tx = tcc();
+    tx.save(&db).await.unwrap();
+    tx.update_state(&db, State::Failed).await.unwrap();
+    assert!(db.oldest_due().await.unwrap().is_none());
+
+    let rocket = rocket::build().mount("/", rocket::routes![luwu::metrics::metrics]);
+    let client = Client::tracked(rocket).await.unwrap();
+    let body = client.get("/metrics").dispatch().await.into_string().await.unwrap();
+    assert!(body.contains("luwu_transactions_created_total{processor=\"tcc\"}"));
+    assert!(body.contains("luwu_transactions_failed_total{processor=\"tcc\"}"));
+    assert!(body.contains("luwu_db_connections_in_use 0"));
+}